@@ -0,0 +1,150 @@
+/// The ways an identifier can be cased, in the order `--preserve-case` prefers them when two give the same text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Casing {
+    Snake,
+    Camel,
+    Pascal,
+    Kebab,
+    Screaming,
+}
+
+const CASINGS: [Casing; 5] = [Casing::Snake, Casing::Camel, Casing::Pascal, Casing::Kebab, Casing::Screaming];
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+
+    match chars.next() {
+        Some(c) => c.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+impl Casing {
+    fn join<S: AsRef<str>>(&self, words: &[S]) -> String {
+        let words = words.iter().map(|w| w.as_ref());
+
+        match self {
+            Casing::Snake => words.collect::<Vec<&str>>().join("_"),
+            Casing::Kebab => words.collect::<Vec<&str>>().join("-"),
+            Casing::Screaming => words.map(str::to_uppercase).collect::<Vec<String>>().join("_"),
+            Casing::Pascal => words.map(capitalize).collect(),
+            Casing::Camel => words
+                .enumerate()
+                .map(|(i, w)| if i == 0 { w.to_string() } else { capitalize(w) })
+                .collect(),
+        }
+    }
+}
+
+/// Splits an identifier like `userId`, `user_id`, `USER-ID` or `HTTPServer` into its words, in lowercase.
+fn split_words(s: &str) -> Vec<String> {
+    let chars: Vec<char> = s.chars().collect();
+    let mut words = Vec::new();
+    let mut current = String::new();
+
+    for (i, &c) in chars.iter().enumerate() {
+        if !c.is_alphanumeric() {
+            if !current.is_empty() {
+                words.push(std::mem::take(&mut current));
+            }
+            continue;
+        }
+
+        let prev = i.checked_sub(1).map(|j| chars[j]);
+        let next = chars.get(i + 1);
+        let starts_word = c.is_uppercase()
+            && match prev {
+                Some(p) if p.is_lowercase() || p.is_numeric() => true,
+                Some(p) if p.is_uppercase() => next.is_some_and(|n| n.is_lowercase()),
+                _ => false,
+            };
+
+        if starts_word && !current.is_empty() {
+            words.push(std::mem::take(&mut current));
+        }
+        current.extend(c.to_lowercase());
+    }
+
+    if !current.is_empty() {
+        words.push(current);
+    }
+
+    words
+}
+
+/// Pairs up every casing of `pattern` with the same casing of `replacement`, leaving out casings that give the same
+/// pattern text as an earlier one. The pair as given is only added when the pattern isn't cased any of those ways.
+pub fn case_variants(pattern: &str, replacement: &str) -> Vec<(String, String)> {
+    let (from, to) = (split_words(pattern), split_words(replacement));
+    let mut variants: Vec<(String, String)> = Vec::new();
+
+    for casing in CASINGS.iter() {
+        let variant = casing.join(&from);
+
+        if !variant.is_empty() && !variants.iter().any(|(p, _)| *p == variant) {
+            variants.push((variant, casing.join(&to)));
+        }
+    }
+
+    if !variants.iter().any(|(p, _)| p == pattern) {
+        variants.push((pattern.to_string(), replacement.to_string()));
+    }
+
+    variants
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_words() {
+        let cases = vec![
+            ("user_id", vec!["user", "id"]),
+            ("userId", vec!["user", "id"]),
+            ("UserId", vec!["user", "id"]),
+            ("USER-ID", vec!["user", "id"]),
+            ("HTTPServer2Go", vec!["http", "server2", "go"]),
+            ("user", vec!["user"]),
+            ("", vec![]),
+        ];
+
+        for (input, expected) in cases {
+            debug_assert_eq!(split_words(input), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_case_variants() {
+        let variants = case_variants("user_id", "account_id");
+        let expected = vec![
+            ("user_id", "account_id"),
+            ("userId", "accountId"),
+            ("UserId", "AccountId"),
+            ("user-id", "account-id"),
+            ("USER_ID", "ACCOUNT_ID"),
+        ];
+
+        debug_assert_eq!(
+            variants,
+            expected.into_iter().map(|(a, b)| (a.to_string(), b.to_string())).collect::<Vec<_>>()
+        );
+
+        let variants = case_variants("userId", "account_id");
+        debug_assert!(variants.contains(&("userId".to_string(), "accountId".to_string())));
+        debug_assert!(variants.contains(&("user_id".to_string(), "account_id".to_string())));
+        debug_assert_eq!(variants.len(), 5);
+
+        let variants = case_variants("User_Id", "account_id");
+        debug_assert_eq!(variants.last(), Some(&("User_Id".to_string(), "account_id".to_string())));
+    }
+
+    #[test]
+    fn test_case_variants_of_one_word() {
+        let patterns: Vec<(String, String)> = case_variants("user", "account_id");
+
+        debug_assert_eq!(patterns.len(), 3);
+        debug_assert!(patterns.contains(&("User".to_string(), "AccountId".to_string())));
+        debug_assert!(patterns.contains(&("USER".to_string(), "ACCOUNT_ID".to_string())));
+    }
+}
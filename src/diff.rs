@@ -0,0 +1,26 @@
+use similar::TextDiff;
+
+pub fn unified_diff(path: &str, original: &str, new: &str) -> String {
+    TextDiff::from_lines(original, new)
+        .unified_diff()
+        .context_radius(3)
+        .header(path, path)
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unified_diff() {
+        let diff = unified_diff("./a.txt", "abc\ndef\nghi\n", "abc\nxyz\nghi\n");
+
+        debug_assert_eq!(diff, "--- ./a.txt\n+++ ./a.txt\n@@ -1,3 +1,3 @@\n abc\n-def\n+xyz\n ghi\n");
+    }
+
+    #[test]
+    fn test_unified_diff_no_changes() {
+        debug_assert_eq!(unified_diff("./a.txt", "abc\n", "abc\n"), "");
+    }
+}
@@ -0,0 +1,127 @@
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8};
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// How a file that isn't plain UTF-8 is encoded, and whether it starts with a byte order mark.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileEncoding {
+    pub encoding: &'static Encoding,
+    pub bom: bool,
+}
+
+impl FileEncoding {
+    /// A byte order mark always wins, then the `--encoding` flag. Plain UTF-8 files give `None`,
+    /// since they can be searched without decoding them first.
+    pub fn sniff(head: &[u8], forced: Option<&'static Encoding>) -> Option<FileEncoding> {
+        match Encoding::for_bom(head) {
+            Some((encoding, _)) => Some(FileEncoding { encoding, bom: true }),
+            None => forced
+                .filter(|e| *e != UTF_8)
+                .map(|encoding| FileEncoding { encoding, bom: false }),
+        }
+    }
+
+    pub fn sniff_file(path: &Path, forced: Option<&'static Encoding>) -> io::Result<Option<FileEncoding>> {
+        let mut head = Vec::new();
+        fs::File::open(path)?.take(3).read_to_end(&mut head)?;
+        Ok(FileEncoding::sniff(&head, forced))
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.encoding.name()
+    }
+
+    /// UTF-16 text is full of NUL bytes, so it can't be checked for being binary the usual way.
+    pub fn is_utf16(&self) -> bool {
+        self.encoding == UTF_16LE || self.encoding == UTF_16BE
+    }
+
+    fn bom_bytes(&self) -> &'static [u8] {
+        match (self.bom, self.encoding) {
+            (false, _) => b"",
+            (true, e) if e == UTF_16LE => b"\xff\xfe",
+            (true, e) if e == UTF_16BE => b"\xfe\xff",
+            (true, _) => b"\xef\xbb\xbf",
+        }
+    }
+
+    /// Decodes the contents of a file, without its byte order mark. `None` if they aren't valid in this encoding.
+    pub fn decode(&self, bytes: &[u8]) -> Option<String> {
+        let bytes = bytes.strip_prefix(self.bom_bytes()).unwrap_or(bytes);
+
+        self.encoding
+            .decode_without_bom_handling_and_without_replacement(bytes)
+            .map(|s| s.into_owned())
+    }
+
+    /// Encodes `text` the way the file was, byte order mark included. `None` if it has characters the encoding can't represent.
+    pub fn encode(&self, text: &str) -> Option<Vec<u8>> {
+        let mut out = self.bom_bytes().to_vec();
+
+        if self.encoding == UTF_16LE {
+            out.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+        } else if self.encoding == UTF_16BE {
+            out.extend(text.encode_utf16().flat_map(u16::to_be_bytes));
+        } else {
+            let (bytes, _, unmappable) = self.encoding.encode(text);
+            if unmappable {
+                return None;
+            }
+            out.extend_from_slice(&bytes);
+        }
+
+        Some(out)
+    }
+}
+
+/// Looks up an `--encoding` value, which can be any WHATWG label like `utf-16le`, `latin1` or `shift_jis`.
+pub fn encoding_for_label(label: &str) -> Option<&'static Encoding> {
+    Encoding::for_label(label.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::WINDOWS_1252;
+
+    #[test]
+    fn test_sniff() {
+        let utf16 = FileEncoding { encoding: UTF_16LE, bom: true };
+
+        debug_assert_eq!(FileEncoding::sniff(b"\xff\xfea\0", None), Some(utf16));
+        debug_assert_eq!(FileEncoding::sniff(b"\xff\xfea\0", Some(WINDOWS_1252)), Some(utf16));
+        debug_assert_eq!(FileEncoding::sniff(b"\xef\xbb\xbfa", None).map(|e| e.encoding), Some(UTF_8));
+        debug_assert_eq!(FileEncoding::sniff(b"abc", None), None);
+        debug_assert_eq!(FileEncoding::sniff(b"abc", Some(UTF_8)), None);
+        debug_assert_eq!(
+            FileEncoding::sniff(b"abc", Some(WINDOWS_1252)),
+            Some(FileEncoding { encoding: WINDOWS_1252, bom: false })
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let cases: Vec<(&[u8], &str)> = vec![
+            (b"\xff\xfec\0a\0f\0\xe9\0", "caf\u{e9}"),
+            (b"\xfe\xff\0c\0a\0f\0\xe9", "caf\u{e9}"),
+            (b"\xef\xbb\xbfcaf\xc3\xa9", "caf\u{e9}"),
+        ];
+
+        for (bytes, text) in cases {
+            let encoding = FileEncoding::sniff(bytes, None).unwrap();
+
+            debug_assert_eq!(encoding.decode(bytes).unwrap(), text);
+            debug_assert_eq!(encoding.encode(text).unwrap(), bytes);
+        }
+    }
+
+    #[test]
+    fn test_latin1() {
+        let encoding = FileEncoding::sniff(b"caf\xe9", encoding_for_label("latin1")).unwrap();
+
+        debug_assert_eq!(encoding.decode(b"caf\xe9").unwrap(), "caf\u{e9}");
+        debug_assert_eq!(encoding.encode("caf\u{e9}").unwrap(), b"caf\xe9");
+        debug_assert_eq!(encoding.encode("\u{3042}"), None);
+    }
+}
@@ -1,41 +1,106 @@
 use crate::FarMode;
-use fancy_regex::Regex;
-use crate::iter::dir_iter::{DirIterator, DirIteratorError};
+use crate::file::{is_leftover, is_old_leftover, recover_leftover, Recovery};
+use crate::input::FarOptions;
+use crate::interactive::Prompter;
+use crate::journal::undo_last_run;
+use crate::pattern::Pattern;
+use crate::iter::dir_iter::{DirIterator, DirIteratorError, VisitedFiles, WalkOptions};
 use crate::iter::concat::Concat;
-use crate::replace::{replace_all_in_file, replace_lines_in_file, ReplaceError};
+use crate::replace::{
+    commit_swaps, discard_swaps, replace_all_in_file, replace_bytes_in_file, replace_interactively_in_file,
+    replace_lines_in_file, replace_rules_in_file, FileReport, PendingSwap, ReplaceError
+};
+use crate::rules::Rule;
+use crate::summary::Summary;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 
 use rayon::prelude::*;
 
 fn handle_diriteratorerror(die: DirIteratorError) {
-    eprintln!("{}: {}", die.path, die.err)
+    eprintln!("{}: {}", die.path.display(), die.err)
 }
 
-fn handle_replaceerror(path: &str, re: ReplaceError) {
-    eprintln!("{}: {}", path, re)
+fn handle_replaceerror(path: &Path, re: ReplaceError) {
+    eprintln!("{}: {}", path.display(), re)
 }
 
-fn handle_result(result: Result<String, DirIteratorError>, pattern: &Regex, replacement: &str, mode: FarMode) {
+fn print_dry_run_report(path: &Path, report: &FileReport) {
+    if report.matches() == 0 {
+        return;
+    }
+
+    let mut lines = report.match_lines.clone();
+    lines.dedup();
+
+    let line_word = if lines.len() == 1 { "line" } else { "lines" };
+    let lines = lines.iter().map(|l| l.to_string()).collect::<Vec<String>>().join(", ");
+    let match_word = if report.matches() == 1 { "match" } else { "matches" };
+
+    println!("{}: {} {} ({} {})", path.display(), report.matches(), match_word, line_word, lines)
+}
+
+/// How a file went, along with its new contents if they're waiting on the rest of an `--atomic-run`.
+type Outcome = (Summary, Vec<PendingSwap>);
+
+fn merge_outcomes((a, mut a_swaps): Outcome, (b, b_swaps): Outcome) -> Outcome {
+    a_swaps.extend(b_swaps);
+    (a + b, a_swaps)
+}
+
+fn handle_result(result: Result<PathBuf, DirIteratorError>, pattern: &Pattern, replacement: &str, mode: FarMode, options: &FarOptions) -> Outcome {
+    handle_path(result, options, |path| match (pattern, mode) {
+        (Pattern::Text(re), FarMode::Lines) => replace_lines_in_file(path, re, replacement, options),
+        (Pattern::Text(re), FarMode::All) => replace_all_in_file(path, re, replacement, options),
+        (Pattern::Bytes(re), _) => replace_bytes_in_file(path, re, replacement, mode, options)
+    })
+}
+
+/// A single rule is streamed through like always, while several rules need the whole file in memory.
+fn handle_rules(result: Result<PathBuf, DirIteratorError>, rules: &[Rule], options: &FarOptions) -> Outcome {
+    match rules {
+        [rule] => handle_result(result, &rule.pattern, &rule.replacement, rule.mode, options),
+        _ => handle_path(result, options, |path| replace_rules_in_file(path, rules, options))
+    }
+}
+
+/// Runs `replace` on the path and reports how it went.
+fn handle_path<F>(result: Result<PathBuf, DirIteratorError>, options: &FarOptions, replace: F) -> Outcome
+where
+    F: FnOnce(&Path) -> Result<FileReport, ReplaceError>
+{
     let path = match result {
         Ok(v) => v,
-        Err(e) => return handle_diriteratorerror(e)
-    };
-
-    let f = match mode {
-        FarMode::Lines => replace_lines_in_file,
-        FarMode::All => replace_all_in_file
+        Err(e) => {
+            handle_diriteratorerror(e);
+            return (Summary::walk_error(), Vec::new());
+        }
     };
 
-    match f(&path, pattern, replacement) {
-        Ok(_) => {},
-        Err(e) => return handle_replaceerror(&path, e)
+    match replace(&path) {
+        Ok(report) => {
+            if let Some(diff) = &report.diff {
+                print!("{}", diff)
+            } else if options.dry_run {
+                print_dry_run_report(&path, &report)
+            }
+            (Summary::from_report(&report), report.pending.into_iter().collect())
+        },
+        Err(e) => {
+            let summary = Summary::from_error(&e);
+            handle_replaceerror(&path, e);
+            (summary, Vec::new())
+        }
     }
 }
 
-pub fn diriter_vec<S: AsRef<str>, I: Iterator<Item=S>>(dirs: I) -> Result<impl Iterator<Item=Result<String, DirIteratorError>>, DirIteratorError> {
+pub fn diriter_vec<S: AsRef<Path>, I: Iterator<Item=S>>(dirs: I, options: &WalkOptions) -> Result<impl Iterator<Item=Result<PathBuf, DirIteratorError>>, DirIteratorError> {
     let mut vec = Vec::new();
+    let visited = VisitedFiles::default();
 
     for dir in dirs {
-        match DirIterator::new(dir.as_ref()) {
+        match DirIterator::new(dir.as_ref(), options, &visited) {
             Ok(di) => vec.push(di),
             Err(e) => return Err(e)
         }
@@ -44,30 +109,179 @@ pub fn diriter_vec<S: AsRef<str>, I: Iterator<Item=S>>(dirs: I) -> Result<impl I
     Ok(Concat::new(vec))
 }
 
-pub fn find_and_replace<S: AsRef<str>, I: IntoIterator<Item=S>>(dirs: I, pattern: &Regex, replacement: &str, mode: FarMode) {
-    let iter = match diriter_vec(dirs.into_iter()) {
+/// Whether `path` is one of the files a run writes next to the ones it changes: a temp file, or a backup with
+/// `--backup=suffix`. The walk can come across those while the run is still going, and they aren't input.
+fn is_own_file(path: &Path, options: &FarOptions) -> bool {
+    let is_backup = match options.backup.as_ref().and_then(|b| b.suffix.as_ref()) {
+        Some(suffix) => path.file_name().is_some_and(|name| name.to_string_lossy().ends_with(suffix.as_str())),
+        None => false,
+    };
+
+    is_backup || is_leftover(path)
+}
+
+pub fn find_and_replace<S: AsRef<Path>, I: IntoIterator<Item=S>>(dirs: I, rules: &[Rule], options: &FarOptions) -> Summary {
+    let start = Instant::now();
+
+    let iter = match diriter_vec(dirs.into_iter(), &options.walk) {
+        Ok(v) => v.filter(|r| !matches!(r, Ok(path) if is_own_file(path, options))),
+        Err(e) => {
+            handle_diriteratorerror(e);
+            return Summary { elapsed: start.elapsed(), ..Summary::walk_error() };
+        }
+    };
+
+    let (summary, swaps) = match options.interactive {
+        true => {
+            let stdin = io::stdin();
+            let mut prompter = Prompter::new(stdin.lock(), io::stdout());
+            let rule = rules.first().expect("--interactive can't be combined with --file");
+            replace_interactively(iter, &rule.pattern, &rule.replacement, rule.mode, options, &mut prompter)
+        },
+        false => iter.par_bridge()
+            .map(|r| handle_rules(r, rules, options))
+            .reduce(|| (Summary::default(), Vec::new()), merge_outcomes)
+    };
+
+    let summary = match options.atomic_run {
+        true => finish_atomic_run(summary, &swaps, options),
+        false => summary
+    };
+
+    Summary { elapsed: start.elapsed(), ..summary }
+}
+
+/// Swaps in every file of an `--atomic-run`, unless any file failed. Skipped files don't count as failures.
+fn finish_atomic_run(summary: Summary, swaps: &[PendingSwap], options: &FarOptions) -> Summary {
+    let unchanged = Summary { files_changed: 0, replacements: 0, ..summary.clone() };
+
+    if summary.errors > 0 {
+        discard_swaps(swaps);
+        eprintln!("Nothing was changed, since some files failed.");
+        return unchanged;
+    }
+
+    match commit_swaps(swaps, options) {
+        Ok(()) => summary,
+        Err((path, e)) => {
+            eprintln!("{}: {}", path.display(), e);
+            eprintln!("Nothing was changed, since this file couldn't be replaced.");
+            unchanged + Summary::walk_error()
+        }
+    }
+}
+
+/// `--interactive` goes through the files one at a time, so the prompts come in order.
+fn replace_interactively<I, R, W>(iter: I, pattern: &Pattern, replacement: &str, mode: FarMode, options: &FarOptions, prompter: &mut Prompter<R, W>) -> Outcome
+where
+    I: Iterator<Item=Result<PathBuf, DirIteratorError>>,
+    R: BufRead,
+    W: Write
+{
+    let pattern = match pattern {
+        Pattern::Text(re) => re,
+        Pattern::Bytes(_) => unreachable!("--interactive can't be combined with --binary")
+    };
+
+    let mut outcome = (Summary::default(), Vec::new());
+    for result in iter {
+        outcome = merge_outcomes(outcome, handle_path(result, options, |path| {
+            replace_interactively_in_file(path, pattern, replacement, mode, options, |text, range, new| {
+                prompter.ask(path, text, range, new)
+            })
+        }));
+
+        if prompter.quit() {
+            break;
+        }
+    }
+
+    outcome
+}
+
+/// Prints the summary of a run to stderr, so it doesn't end up in a diff piped somewhere else.
+pub fn print_summary(summary: &Summary, options: &FarOptions) {
+    if options.stats {
+        eprint!("{}", summary.details())
+    } else {
+        eprintln!("{}", summary)
+    }
+}
+
+/// Returns the number of errors, counting both paths that couldn't be walked and leftovers that couldn't be recovered.
+pub fn recover<S: AsRef<Path>, I: IntoIterator<Item=S>>(dirs: I, options: &WalkOptions) -> usize {
+    let iter = match diriter_vec(dirs.into_iter(), options) {
         Ok(v) => v,
-        Err(e) => return handle_diriteratorerror(e)
+        Err(e) => {
+            handle_diriteratorerror(e);
+            return 1;
+        }
     };
 
-    iter.par_bridge()
-        .for_each(|r| handle_result(r, pattern, replacement, mode));
+    let mut errors = 0;
+    let mut leftovers = Vec::new();
+    for result in iter {
+        match result {
+            Ok(path) => if is_leftover(&path) {
+                leftovers.push(path)
+            },
+            Err(e) => {
+                handle_diriteratorerror(e);
+                errors += 1;
+            }
+        }
+    }
+
+    // An old file has to be restored before a new file next to it can be judged.
+    leftovers.sort_by_key(|path| !is_old_leftover(path));
+
+    for path in leftovers {
+        match recover_leftover(&path) {
+            Ok(Some(Recovery::Removed)) => println!("removed {}", path.display()),
+            Ok(Some(Recovery::Restored(target))) => println!("restored {} from {}", target.display(), path.display()),
+            Ok(None) => {},
+            Err(e) => {
+                eprintln!("{}: {}", path.display(), e);
+                errors += 1;
+            }
+        }
+    }
+
+    errors
+}
+
+/// Returns the number of errors, which is 1 if the undo was refused or failed partway.
+pub fn undo(journal: &Path) -> usize {
+    match undo_last_run(journal) {
+        Ok(restored) => {
+            for path in restored {
+                println!("restored {}", path.display())
+            }
+            0
+        },
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::testdir::testdir::{TestFile};
-    use std::fs::read_to_string;
+    use crate::journal::Backup;
+    use crate::pattern::PatternOptions;
+    use crate::testdir::testdir::{TestDir, TestFile};
+    use std::fs::{self, read_to_string};
 
     #[test]
     pub fn test_handle_result_basic() {
         let file = TestFile::new("abc def abc");
-        let re = fancy_regex::Regex::new("abc").unwrap();
+        let re = Pattern::text("abc").unwrap();
 
-        handle_result(Ok(file.path_str()), &re, "def", FarMode::All);
+        handle_result(Ok(file.path().to_path_buf()), &re, "def", FarMode::All, &FarOptions::default());
 
-        let new_contents = read_to_string(&file.path_str()).unwrap();
+        let new_contents = read_to_string(file.path_str()).unwrap();
 
         debug_assert_eq!(new_contents, "def def def");
     }
@@ -75,11 +289,11 @@ mod tests {
     #[test]
     pub fn test_handle_result_traverses_lines_in_multiline_mode() {
         let file = TestFile::new("abc ab\nc abc");
-        let re = fancy_regex::Regex::new("[^ ]+").unwrap();
+        let re = Pattern::text("[^ ]+").unwrap();
 
-        handle_result(Ok(file.path_str()), &re, "def", FarMode::All);
+        handle_result(Ok(file.path().to_path_buf()), &re, "def", FarMode::All, &FarOptions::default());
 
-        let new_contents = read_to_string(&file.path_str()).unwrap();
+        let new_contents = read_to_string(file.path_str()).unwrap();
 
         debug_assert_eq!(new_contents, "def def def");
     }
@@ -87,12 +301,185 @@ mod tests {
     #[test]
     pub fn test_handle_result_doesnt_traverse_lines_in_singleline_mode() {
         let file = TestFile::new("abc ab\nc abc");
-        let re = fancy_regex::Regex::new("[^ ]+").unwrap();
+        let re = Pattern::text("[^ ]+").unwrap();
 
-        handle_result(Ok(file.path_str()), &re, "def", FarMode::Lines);
+        handle_result(Ok(file.path().to_path_buf()), &re, "def", FarMode::Lines, &FarOptions::default());
 
-        let new_contents = read_to_string(&file.path_str()).unwrap();
+        let new_contents = read_to_string(file.path_str()).unwrap();
 
         debug_assert_eq!(new_contents, "def def\ndef def");
     }
-}
\ No newline at end of file
+
+    #[test]
+    pub fn test_handle_result_dry_run_leaves_file_alone() {
+        let file = TestFile::new("abc ab\nc abc");
+        let re = Pattern::text("abc").unwrap();
+        let options = FarOptions { dry_run: true, ..FarOptions::default() };
+
+        handle_result(Ok(file.path().to_path_buf()), &re, "def", FarMode::Lines, &options);
+        handle_result(Ok(file.path().to_path_buf()), &re, "def", FarMode::All, &options);
+
+        let new_contents = read_to_string(file.path_str()).unwrap();
+
+        debug_assert_eq!(new_contents, "abc ab\nc abc");
+    }
+
+    #[test]
+    pub fn test_handle_result_skips_binary_files_unless_asked() {
+        let file = TestFile::new(b"abc\0\xff");
+        let path = || Ok(file.path().to_path_buf());
+
+        handle_result(path(), &Pattern::text("abc").unwrap(), "def", FarMode::All, &FarOptions::default());
+        debug_assert_eq!(std::fs::read(file.path()).unwrap(), b"abc\0\xff");
+
+        handle_result(path(), &Pattern::bytes("abc", false).unwrap(), "def", FarMode::Lines, &FarOptions::default());
+        debug_assert_eq!(std::fs::read(file.path()).unwrap(), b"def\0\xff");
+    }
+
+    #[test]
+    pub fn test_find_and_replace_sums_up_files() {
+        let mut dir = TestDir::new();
+        dir.file("changed", "abc abc\nabc").file("unchanged", "def").file("binary", "abc\0");
+
+        let rules = [Rule::new("abc", "def", FarMode::Lines, &PatternOptions::default()).unwrap()];
+        let summary = find_and_replace(vec![dir.path()], &rules, &FarOptions::default());
+
+        debug_assert_eq!(summary.files_scanned, 3);
+        debug_assert_eq!(summary.files_matched, 1);
+        debug_assert_eq!(summary.files_changed, 1);
+        debug_assert_eq!(summary.replacements, 3);
+        debug_assert_eq!(summary.skipped_not_printable, 1);
+        debug_assert_eq!(summary.errors, 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    pub fn test_find_and_replace_follows_each_file_once() {
+        let mut dir = TestDir::new();
+        dir.subdir("L", |l| {
+            l.file("a", "x").symlink("b", "a");
+        });
+
+        let rules = [Rule::new("x", "xx", FarMode::Lines, &PatternOptions::default()).unwrap()];
+        let mut options = FarOptions::default();
+        options.walk.follow = true;
+        let l = dir.path().join("L");
+        let summary = find_and_replace(vec![&l, &l], &rules, &options);
+
+        debug_assert_eq!(read_to_string(l.join("a")).unwrap(), "xx");
+        debug_assert_eq!(summary.files_scanned, 1);
+    }
+
+    /// Enough files that the directory is still being read while the first ones are replaced.
+    fn _many_files(dir: &mut TestDir, contents: &str) {
+        for i in 0..3000 {
+            dir.file(&format!("file_{}", i), contents);
+        }
+    }
+
+    #[test]
+    pub fn test_atomic_run_skips_its_own_temp_files() {
+        let mut dir = TestDir::new();
+        _many_files(&mut dir, "abc\n");
+
+        let rules = [Rule::new("abc", "abcX", FarMode::Lines, &PatternOptions::default()).unwrap()];
+        let options = FarOptions { atomic_run: true, ..FarOptions::default() };
+        let summary = find_and_replace(vec![dir.path()], &rules, &options);
+
+        debug_assert_eq!(summary.files_scanned, 3000);
+        debug_assert_eq!(summary.files_changed, 3000);
+        debug_assert_eq!(summary.errors, 0);
+        debug_assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 3000);
+        debug_assert_eq!(read_to_string(dir.path().join("file_1000")).unwrap(), "abcX\n");
+    }
+
+    #[test]
+    pub fn test_backup_skips_its_own_backups() {
+        let mut dir = TestDir::new();
+        _many_files(&mut dir, "abc\n");
+        let journal = TestDir::new();
+
+        let rules = [Rule::new("abc", "def", FarMode::Lines, &PatternOptions::default()).unwrap()];
+        let backup = Backup::new(journal.path(), Some(".bak".to_string()));
+        let options = FarOptions { backup: Some(backup), ..FarOptions::default() };
+        let summary = find_and_replace(vec![dir.path()], &rules, &options);
+
+        debug_assert_eq!(summary.files_changed, 3000);
+        debug_assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 6000);
+        debug_assert_eq!(read_to_string(dir.path().join("file_1000.bak")).unwrap(), "abc\n");
+
+        undo(journal.path());
+        debug_assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 3000);
+        debug_assert_eq!(read_to_string(dir.path().join("file_1000")).unwrap(), "abc\n");
+    }
+
+    #[test]
+    pub fn test_replace_interactively() {
+        let mut dir = TestDir::new();
+        dir.file("a", "abc abc\nabc\n").file("b", "abc");
+
+        let mut output = Vec::new();
+        let mut prompter = Prompter::new("n\ne\nxyz\ny\nq\n".as_bytes(), &mut output);
+        let iter = diriter_vec(vec![dir.path()].into_iter(), &WalkOptions::default()).unwrap();
+        let mut paths: Vec<_> = iter.collect();
+        paths.sort_by_key(|r| r.as_ref().unwrap().clone());
+
+        let (summary, _) = replace_interactively(paths.into_iter(), &Pattern::text("abc").unwrap(), "def", FarMode::Lines, &FarOptions::default(), &mut prompter);
+
+        debug_assert_eq!(read_to_string(dir.path().join("a")).unwrap(), "abc xyz\ndef\n");
+        debug_assert_eq!(read_to_string(dir.path().join("b")).unwrap(), "abc");
+        debug_assert_eq!(summary.replacements, 2);
+        debug_assert_eq!(summary.files_scanned, 2);
+    }
+
+    #[test]
+    pub fn test_atomic_run_changes_nothing_if_a_file_fails() {
+        let mut dir = TestDir::new();
+        dir.file("a", "\u{feff}abc").file("b", "abc");
+        let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+
+        // `b` is read as latin1, which can't hold the replacement, while `a` has a UTF-8 byte order mark.
+        let rules = [Rule::new("abc", "\u{3042}", FarMode::All, &PatternOptions::default()).unwrap()];
+        let options = FarOptions { encoding: encoding_rs::Encoding::for_label(b"latin1"), atomic_run: true, ..FarOptions::default() };
+
+        let summary = find_and_replace(vec![dir.path()], &rules, &options);
+
+        debug_assert_eq!(summary.errors, 1);
+        debug_assert_eq!(summary.files_changed, 0);
+        debug_assert_eq!(read_to_string(&a).unwrap(), "\u{feff}abc");
+        debug_assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+
+        std::fs::write(&b, "xyz").unwrap();
+        let summary = find_and_replace(vec![dir.path()], &rules, &options);
+
+        debug_assert_eq!(summary.files_changed, 1);
+        debug_assert_eq!(read_to_string(&a).unwrap(), "\u{feff}\u{3042}");
+        debug_assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    pub fn test_find_and_replace_applies_rules_in_order() {
+        let mut dir = TestDir::new();
+        dir.file("a", "user_id\nUSER\n");
+
+        let rules = crate::rules::parse_rules("s/user/account/i\ns/account_id/account_key/\ns/account\\n$//m\n", FarMode::Lines, &PatternOptions::default()).unwrap();
+        let summary = find_and_replace(vec![dir.path()], &rules, &FarOptions::default());
+
+        debug_assert_eq!(read_to_string(dir.path().join("a")).unwrap(), "account_key\n");
+        debug_assert_eq!(summary.files_changed, 1);
+        debug_assert_eq!(summary.replacements, 4);
+    }
+
+    #[test]
+    pub fn test_find_and_replace_applies_rules_simultaneously() {
+        let mut dir = TestDir::new();
+        dir.file("a", "a b ab\nba\n");
+
+        let rules = crate::rules::parse_rules("s/a/b/\ns/b/a/\ns/ab/x/\n", FarMode::Lines, &PatternOptions::default()).unwrap();
+        let options = FarOptions { simultaneous: true, ..FarOptions::default() };
+        let summary = find_and_replace(vec![dir.path()], &rules, &options);
+
+        debug_assert_eq!(read_to_string(dir.path().join("a")).unwrap(), "b a ba\nab\n");
+        debug_assert_eq!(summary.replacements, 6);
+    }
+}
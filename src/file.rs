@@ -1,13 +1,23 @@
 use std::fs;
-use std::io::{ErrorKind, Result};
+use std::io::{Error, ErrorKind, Result};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// The suffix of the temp files that replacements are written to before being swapped in.
+pub const NEW_SUFFIX: &str = ".new";
+
+/// The suffix that older versions moved the original file to during the swap.
+pub const OLD_SUFFIX: &str = ".old";
 
 pub struct TempFile {
     pub file: fs::File,
-    pub filename: String,
+    pub filename: PathBuf,
+    keep: bool,
 }
 
 impl TempFile {
-    pub fn new(filename: &str, suffix: &str) -> Result<Self> {
+    pub fn new<P: AsRef<Path>>(filename: P, suffix: &str) -> Result<Self> {
+        let filename = filename.as_ref();
         let mut index = 0;
 
         let mut next_fname = || {
@@ -18,12 +28,12 @@ impl TempFile {
         let mut fname = next_fname();
 
         loop {
-            match fs::OpenOptions::new()
+            match open_options()
                 .write(true)
                 .create_new(true)
                 .open(&fname)
             {
-                Ok(f) => return Ok(TempFile {file: f, filename: fname}),
+                Ok(f) => return Ok(TempFile {file: f, filename: fname, keep: false}),
                 Err(e) => match e.kind() {
                     ErrorKind::AlreadyExists => {
                         fname = next_fname();
@@ -34,40 +44,309 @@ impl TempFile {
         }
     }
 
-    pub fn filename(filename: &str, suffix: &str) -> Result<String> {
-        let tmp = Self::new(filename, suffix)?;
-        Ok(tmp.filename.clone())
+    /// Closes the file but leaves it in place instead of removing it, so it can be swapped in later.
+    pub fn keep(mut self) -> PathBuf {
+        self.keep = true;
+        self.filename.clone()
     }
 }
 
 impl Drop for TempFile {
     fn drop(&mut self) {
-        let _ = fs::remove_file(&self.filename);
+        if !self.keep {
+            let _ = fs::remove_file(&self.filename);
+        }
     }
 }
 
-fn gen_temp_filename(filename: &str, suffix: &str, index: u32) -> String {
-    let mut ret = filename.to_string();
-    ret += suffix;
-    ret += &index.to_string();
-    ret
+/// Temp files start out readable only by their owner, so the contents of a private file are never exposed
+/// before `replace_file` copies the original's permissions over.
+#[cfg(unix)]
+fn open_options() -> fs::OpenOptions {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut options = fs::OpenOptions::new();
+    options.mode(0o600);
+    options
 }
 
-fn copy_file_and_delete_original(from: &str, to: &str) -> Result<()> {
-    fs::copy(from, to)?;
-    fs::remove_file(from)?;
-    Ok(())
+#[cfg(not(unix))]
+fn open_options() -> fs::OpenOptions {
+    fs::OpenOptions::new()
 }
 
-pub fn move_file(from: &str, to: &str) -> Result<()> {
-    fs::rename(from, to).or_else(|_| copy_file_and_delete_original(from, to))?;
-    Ok(())
+fn gen_temp_filename(filename: &Path, suffix: &str, index: u32) -> PathBuf {
+    let mut ret = OsString::from(filename);
+    ret.push(suffix);
+    ret.push(index.to_string());
+    PathBuf::from(ret)
+}
+
+/// Changing the owner is only allowed for root, but changing just the group is allowed if we're in it,
+/// so we fall back to that and otherwise leave the ownership alone.
+#[cfg(unix)]
+fn copy_ownership(metadata: &fs::Metadata, to: &Path) {
+    use std::os::unix::fs::{chown, MetadataExt};
+
+    if chown(to, Some(metadata.uid()), Some(metadata.gid())).is_err() {
+        let _ = chown(to, None, Some(metadata.gid()));
+    }
+}
+
+#[cfg(not(unix))]
+fn copy_ownership(_metadata: &fs::Metadata, _to: &Path) {}
+
+#[cfg(feature = "xattr")]
+fn copy_xattrs(from: &Path, to: &Path) {
+    let names = match xattr::list(from) {
+        Ok(names) => names,
+        Err(_) => return,
+    };
+
+    for name in names {
+        if let Ok(Some(value)) = xattr::get(from, &name) {
+            let _ = xattr::set(to, &name, &value);
+        }
+    }
+}
+
+#[cfg(not(feature = "xattr"))]
+fn copy_xattrs(_from: &Path, _to: &Path) {}
+
+/// Copies the permissions, ownership (where permitted) and, with the `xattr` feature, extended attributes of `from` onto `to`.
+pub fn copy_metadata<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> Result<()> {
+    let (from, to) = (from.as_ref(), to.as_ref());
+    let metadata = fs::metadata(from)?;
+
+    copy_ownership(&metadata, to);
+    copy_xattrs(from, to);
+    fs::set_permissions(to, metadata.permissions())
+}
+
+/// The rename in `replace_file` is only durable once the directory entry itself has been written out.
+#[cfg(unix)]
+fn sync_parent_dir(path: &Path) -> Result<()> {
+    let parent = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+
+    fs::File::open(parent)?.sync_all()
 }
 
-pub fn replace_file(from: &str, to: &str) -> Result<()> {
-    let fname = TempFile::filename(to, ".old")?;
-    move_file(to, &fname)?;
-    move_file(from, to)?;
-    fs::remove_file(&fname)?;
+#[cfg(not(unix))]
+fn sync_parent_dir(_path: &Path) -> Result<()> {
     Ok(())
 }
+
+/// Atomically replaces `to` with `from`, which must be in the same directory (as a `TempFile` is).
+/// `to` always refers to either the complete old file or the complete new one, even if the process dies midway.
+pub fn replace_file<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> Result<()> {
+    let (from, to) = (from.as_ref(), to.as_ref());
+
+    if fs::symlink_metadata(to)?.file_type().is_symlink() {
+        return Err(Error::new(ErrorKind::InvalidInput, "Refusing to replace a symbolic link"));
+    }
+
+    copy_metadata(to, from)?;
+
+    fs::File::open(from)?.sync_all()?;
+    fs::rename(from, to)?;
+    sync_parent_dir(to)
+}
+
+/// The file that writes to `path` should go to: the target of the link if `path` is a symbolic link, or `path` itself.
+pub fn write_target(path: &Path) -> Result<PathBuf> {
+    if !fs::symlink_metadata(path)?.file_type().is_symlink() {
+        return Ok(path.to_path_buf());
+    }
+
+    fs::canonicalize(path)
+}
+
+/// What `recover_leftover` did with a file left behind by an interrupted run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Recovery {
+    Removed,
+    Restored(PathBuf),
+}
+
+fn leftover_target(path: &Path, suffix: &str) -> Option<PathBuf> {
+    let extension = path.extension()?.to_str()?;
+    let index = extension.strip_prefix(suffix.trim_start_matches('.'))?;
+
+    if index.is_empty() || !index.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    Some(path.with_extension(""))
+}
+
+pub fn is_leftover(path: &Path) -> bool {
+    is_old_leftover(path) || leftover_target(path, NEW_SUFFIX).is_some()
+}
+
+pub fn is_old_leftover(path: &Path) -> bool {
+    leftover_target(path, OLD_SUFFIX).is_some()
+}
+
+/// Cleans up a temp file left behind by an interrupted run.
+///
+/// A leftover new file is only removed if the file it was replacing is still there.
+/// A leftover old file is the original contents, so it's moved back into place if the swap never finished,
+/// and removed otherwise. Old files should therefore be recovered before new ones.
+pub fn recover_leftover(path: &Path) -> Result<Option<Recovery>> {
+    if let Some(target) = leftover_target(path, OLD_SUFFIX) {
+        if target.exists() {
+            fs::remove_file(path)?;
+            return Ok(Some(Recovery::Removed));
+        }
+
+        fs::rename(path, &target)?;
+        sync_parent_dir(&target)?;
+        return Ok(Some(Recovery::Restored(target)));
+    }
+
+    if let Some(target) = leftover_target(path, NEW_SUFFIX) {
+        if target.exists() {
+            fs::remove_file(path)?;
+            return Ok(Some(Recovery::Removed));
+        }
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::testdir::{TestDir, TestFile};
+    use std::io::Write;
+
+    fn replace_contents(path: &Path, contents: &str) {
+        let mut tmp = TempFile::new(path, NEW_SUFFIX).unwrap();
+        tmp.file.write_all(contents.as_bytes()).unwrap();
+        replace_file(&tmp.filename, path).unwrap();
+    }
+
+    #[test]
+    fn test_replace_file() {
+        let file = TestFile::new("abc");
+
+        replace_contents(file.path(), "def");
+
+        debug_assert_eq!(fs::read_to_string(file.path_str()).unwrap(), "def");
+    }
+
+    #[cfg(unix)]
+    fn _test_replace_file_keeps_mode(mode: u32) {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let file = TestFile::new("#!/bin/sh\necho abc\n");
+        let path = file.path();
+        fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+        let before = fs::metadata(path).unwrap();
+
+        replace_contents(path, "#!/bin/sh\necho def\n");
+
+        let after = fs::metadata(path).unwrap();
+        debug_assert_eq!(after.permissions().mode() & 0o7777, mode);
+        debug_assert_eq!(after.uid(), before.uid());
+        debug_assert_eq!(after.gid(), before.gid());
+        debug_assert_eq!(fs::read_to_string(path).unwrap(), "#!/bin/sh\necho def\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_replace_file_keeps_executable_script_mode() {
+        _test_replace_file_keeps_mode(0o755);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_replace_file_keeps_private_file_mode() {
+        _test_replace_file_keeps_mode(0o600);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_temp_file_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let file = TestFile::new("abc");
+        let tmp = TempFile::new(file.path(), NEW_SUFFIX).unwrap();
+
+        debug_assert_eq!(fs::metadata(&tmp.filename).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+
+    #[test]
+    fn test_replace_file_leaves_no_temp_files() {
+        let mut temp = TestDir::new();
+        temp.file("a", "abc");
+        replace_contents(&temp.path().join("a"), "def");
+
+        let names: Vec<String> = fs::read_dir(temp.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_str().unwrap().to_string())
+            .collect();
+        debug_assert_eq!(names, vec!["a"]);
+    }
+
+    #[test]
+    fn test_leftover_target() {
+        let target = |path: &str, suffix: &str| leftover_target(Path::new(path), suffix);
+
+        debug_assert_eq!(target("dir/a.rs.new1", NEW_SUFFIX), Some(PathBuf::from("dir/a.rs")));
+        debug_assert_eq!(target("dir/a.rs.old12", OLD_SUFFIX), Some(PathBuf::from("dir/a.rs")));
+        debug_assert_eq!(target("dir/a.rs.new", NEW_SUFFIX), None);
+        debug_assert_eq!(target("dir/a.rs.new1", OLD_SUFFIX), None);
+        debug_assert_eq!(target("dir/a.rs.new1x", NEW_SUFFIX), None);
+        debug_assert_eq!(target(".new1", NEW_SUFFIX), None);
+        debug_assert!(!is_leftover(Path::new("dir/a.rs")));
+    }
+
+    #[test]
+    fn test_recover_leftovers() {
+        let mut temp = TestDir::new();
+        temp.file("a", "new")
+            .file("a.old1", "old")
+            .file("b.old1", "original")
+            .file("b.new1", "partial")
+            .file("c.new1", "orphan");
+        let path = |name: &str| temp.path().join(name);
+
+        debug_assert_eq!(recover_leftover(&path("a.old1")).unwrap(), Some(Recovery::Removed));
+        debug_assert_eq!(recover_leftover(&path("b.old1")).unwrap(), Some(Recovery::Restored(path("b"))));
+        debug_assert_eq!(recover_leftover(&path("b.new1")).unwrap(), Some(Recovery::Removed));
+        debug_assert_eq!(recover_leftover(&path("c.new1")).unwrap(), None);
+
+        debug_assert_eq!(fs::read_to_string(path("a")).unwrap(), "new");
+        debug_assert_eq!(fs::read_to_string(path("b")).unwrap(), "original");
+        debug_assert!(!Path::new(&path("a.old1")).exists());
+        debug_assert!(!Path::new(&path("b.new1")).exists());
+        debug_assert!(Path::new(&path("c.new1")).exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_replace_file_refuses_symlinks() {
+        let mut temp = TestDir::new();
+        temp.file("a", "abc").symlink("b", "a");
+        let link = temp.path().join("b");
+
+        let tmp = TempFile::new(&link, NEW_SUFFIX).unwrap();
+        debug_assert!(replace_file(&tmp.filename, &link).is_err());
+        debug_assert!(fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_target() {
+        let mut temp = TestDir::new();
+        temp.file("a", "abc").symlink("b", "a");
+        let path = |name: &str| fs::canonicalize(temp.path()).unwrap().join(name);
+
+        debug_assert_eq!(write_target(&path("a")).unwrap(), path("a"));
+        debug_assert_eq!(write_target(&path("b")).unwrap(), path("a"));
+    }
+}
@@ -1,6 +1,16 @@
-use fancy_regex::{self, Regex};
+use crate::encoding::encoding_for_label;
+use crate::iter::dir_iter::WalkOptions;
+use crate::iter::filter::{build_overrides, build_types, TypeSelection};
+use crate::iter::lines::LineEnding;
+use crate::journal::{journal_dir, Backup};
+use crate::pattern::{CaseMode, Pattern, PatternError, PatternOptions};
+use crate::rules::{read_rules, Rule, RulesError};
+use encoding_rs::Encoding;
+use std::convert::TryFrom;
 use std::env;
+use std::ffi::{OsStr, OsString};
 use std::fmt::Display;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FarMode {
@@ -8,12 +18,79 @@ pub enum FarMode {
     All,
 }
 
+/// Like git, only the start of a file is checked for NUL bytes when deciding whether it's binary.
+pub const DEFAULT_BINARY_SCAN: usize = 8000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FarOptions {
+    pub dry_run: bool,
+    pub diff: bool,
+    /// Ask before replacing each match.
+    pub interactive: bool,
+    /// Only change files once every file has been written to a temp file without errors.
+    pub atomic_run: bool,
+    /// Apply every rule in a single pass over each file, instead of one after the other.
+    pub simultaneous: bool,
+    /// Keep a copy of every file that's changed, so the run can be undone.
+    pub backup: Option<Backup>,
+    /// Print a breakdown of the run instead of a one-line summary.
+    pub stats: bool,
+    /// The ending every line is rewritten with, or `None` to keep each line's own.
+    pub eol: Option<LineEnding>,
+    /// Files bigger than this many bytes are skipped. There's no limit by default.
+    pub max_filesize: Option<u64>,
+    /// How many bytes at the start of a file are checked for a NUL byte. Files with one are skipped as binary.
+    pub binary_scan: usize,
+    /// The encoding of files without a byte order mark. `None` means UTF-8.
+    pub encoding: Option<&'static Encoding>,
+    pub walk: WalkOptions,
+}
+
+impl Default for FarOptions {
+    fn default() -> Self {
+        FarOptions {
+            dry_run: false,
+            diff: false,
+            interactive: false,
+            atomic_run: false,
+            simultaneous: false,
+            backup: None,
+            stats: false,
+            eol: None,
+            max_filesize: None,
+            binary_scan: DEFAULT_BINARY_SCAN,
+            encoding: None,
+            walk: WalkOptions::default(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum ArgsError {
+    InvalidBinaryRegex(regex::Error),
+    InvalidFileType(ignore::Error),
+    InvalidGlob(ignore::Error),
     InvalidRegex(fancy_regex::Error),
+    InvalidRules(String, RulesError),
+    InvalidValue(String, String),
+    IncompatibleFlags(&'static str, &'static str),
+    MissingValue(String),
     NoArgsGiven,
+    NoJournalDir,
+    NotUnicode(OsString),
     OnlyPatternGiven,
     UnrecognizedArgument(String),
+    /// A replacement that doesn't follow a pattern of its own.
+    UnpairedReplacement(String),
+}
+
+impl From<PatternError> for ArgsError {
+    fn from(e: PatternError) -> Self {
+        match e {
+            PatternError::Text(e) => ArgsError::InvalidRegex(e),
+            PatternError::Bytes(e) => ArgsError::InvalidBinaryRegex(e),
+        }
+    }
 }
 
 impl Display for ArgsError {
@@ -22,12 +99,26 @@ impl Display for ArgsError {
             f,
             "{}",
             match self {
-                ArgsError::InvalidRegex(e) => format!("Invalid regex: {}", e.to_string()),
+                ArgsError::InvalidBinaryRegex(e) => format!("Invalid regex: {}", e),
+                ArgsError::InvalidFileType(e) => format!("Invalid file type: {}", e),
+                ArgsError::InvalidGlob(e) => format!("Invalid glob: {}", e),
+                ArgsError::InvalidRegex(e) => format!("Invalid regex: {}", e),
+                ArgsError::InvalidRules(path, e) => format!("Invalid rules file {}: {}", path, e),
+                ArgsError::InvalidValue(flag, value) =>
+                    format!("'{}' is not a valid value for '{}'", value, flag),
+                ArgsError::IncompatibleFlags(a, b) => format!("'{}' can't be used with '{}'", a, b),
+                ArgsError::MissingValue(s) => format!("The flag '{}' needs a value", s),
                 ArgsError::NoArgsGiven => "No arguments were given.".to_string(),
+                ArgsError::NoJournalDir =>
+                    "There's nowhere to keep the journal. Set FAR_JOURNAL_DIR or HOME.".to_string(),
+                ArgsError::NotUnicode(s) =>
+                    format!("The argument '{}' is not valid unicode", s.to_string_lossy()),
                 ArgsError::OnlyPatternGiven =>
                     "A pattern was given but not a substitution.".to_string(),
                 ArgsError::UnrecognizedArgument(s) =>
                     format!("The argument '{}' is unrecognized", s),
+                ArgsError::UnpairedReplacement(s) =>
+                    format!("The replacement '{}' doesn't follow a pattern given with '-e'", s),
             }
         )
     }
@@ -35,47 +126,202 @@ impl Display for ArgsError {
 
 #[derive(Debug)]
 pub struct Args {
-    pub pattern: Regex,
-    pub replacement: String,
-    pub paths: Vec<String>,
-    pub mode: FarMode,
+    /// Applied one after the other, so each rule sees what the ones before it left.
+    pub rules: Vec<Rule>,
+    pub paths: Vec<OsString>,
+    pub options: FarOptions,
+}
+
+#[derive(Debug)]
+pub enum Command {
+    Replace(Box<Args>),
+    Recover(Vec<OsString>, WalkOptions),
+    Undo(PathBuf),
+}
+
+/// Where rules come from, in the order they were given on the command line.
+#[derive(Debug)]
+enum RuleSource {
+    /// A `-e` pattern, and the `-r` replacement after it once it's been given.
+    Expression(String, Option<String>),
+    File(String),
+}
+
+impl RuleSource {
+    fn flag(&self) -> &'static str {
+        match self {
+            RuleSource::Expression(_, _) => "--regexp",
+            RuleSource::File(_) => "--file",
+        }
+    }
 }
 
 #[derive(Debug)]
 struct IncompleteArgs {
-    pattern: Option<Regex>,
-    replacement: Option<String>,
-    paths: Vec<String>,
+    positionals: Vec<OsString>,
     mode: FarMode,
+    options: FarOptions,
+    pending_flag: Option<String>,
     process_flags: bool,
+    pattern_options: PatternOptions,
+    rule_sources: Vec<RuleSource>,
+    recover: bool,
+    /// `Some` once `--backup` is given, holding its suffix if it had one.
+    backup: Option<Option<String>>,
+    undo: bool,
+    type_list: bool,
+}
+
+fn to_str(arg: &OsStr) -> Result<&str, ArgsError> {
+    arg.to_str().ok_or_else(|| ArgsError::NotUnicode(arg.to_os_string()))
+}
+
+/// Parses a size like `512`, `64K`, `10M` or `2G`. The suffixes are powers of 1024.
+fn parse_size(s: &str) -> Option<u64> {
+    let (digits, shift) = match s.char_indices().last()? {
+        (i, 'K') | (i, 'k') => (&s[..i], 10),
+        (i, 'M') | (i, 'm') => (&s[..i], 20),
+        (i, 'G') | (i, 'g') => (&s[..i], 30),
+        _ => (s, 0),
+    };
+
+    digits.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+/// With `--file` or `-e`, every positional argument is a path, and the rules all come from those, in order.
+fn build_rules(sources: &[RuleSource], mode: FarMode, options: &PatternOptions) -> Result<Vec<Rule>, ArgsError> {
+    let mut rules = Vec::new();
+
+    for source in sources {
+        match source {
+            RuleSource::Expression(pattern, Some(replacement)) => rules.push(Rule::new(pattern, replacement, mode, options)?),
+            RuleSource::Expression(pattern, None) => {
+                Pattern::new(pattern, options)?;
+                return Err(ArgsError::OnlyPatternGiven);
+            }
+            RuleSource::File(file) => {
+                let read = read_rules(Path::new(file), mode, options).map_err(|e| ArgsError::InvalidRules(file.clone(), e))?;
+                rules.extend(read);
+            }
+        }
+    }
+
+    Ok(rules)
+}
+
+fn flag_takes_value(flag: &str) -> bool {
+    matches!(flag, "--include" | "--exclude" | "--type" | "-t" | "--type-not" | "-T" | "--type-add" | "--eol" | "--max-filesize" | "--binary-scan" | "--encoding" | "-E" | "--file" | "-f" | "--regexp" | "-e" | "--replacement" | "-r")
 }
 
 impl IncompleteArgs {
     fn new() -> Self {
         IncompleteArgs {
-            pattern: None,
-            replacement: None,
-            paths: Vec::new(),
+            positionals: Vec::new(),
             mode: FarMode::Lines,
+            options: FarOptions::default(),
+            pending_flag: None,
             process_flags: true,
+            pattern_options: PatternOptions::default(),
+            rule_sources: Vec::new(),
+            recover: false,
+            backup: None,
+            undo: false,
+            type_list: false,
         }
     }
 
-    fn handle_positional(&mut self, arg: &str) -> Result<&mut IncompleteArgs, ArgsError> {
-        let arg = arg.to_string();
-
-        match (&self.pattern, &self.replacement) {
-            (None, _) => {
-                self.pattern = Some(Regex::new(&arg).map_err(|e| ArgsError::InvalidRegex(e))?)
-            }
-            (_, None) => self.replacement = Some(arg),
-            (_, _) => self.paths.push(arg),
-        };
+    fn handle_positional(&mut self, arg: &OsStr) -> Result<&mut IncompleteArgs, ArgsError> {
+        self.positionals.push(arg.to_os_string());
+        Ok(self)
+    }
 
+    fn add_glob(&mut self, glob: String) -> Result<&mut IncompleteArgs, ArgsError> {
+        build_overrides(Path::new("."), &[&glob]).map_err(ArgsError::InvalidGlob)?;
+        self.options.walk.globs.push(glob);
         Ok(self)
     }
 
+    fn handle_flag_value(&mut self, flag: &str, value: &str) -> Result<&mut IncompleteArgs, ArgsError> {
+        match flag {
+            "--include" => self.add_glob(value.to_string()),
+            "--exclude" => self.add_glob(format!("!{}", value)),
+            "--type" | "-t" => {
+                self.options.walk.types.push(TypeSelection::Select(value.to_string()));
+                Ok(self)
+            }
+            "--type-not" | "-T" => {
+                self.options.walk.types.push(TypeSelection::Negate(value.to_string()));
+                Ok(self)
+            }
+            "--type-add" => {
+                self.options.walk.type_defs.push(value.to_string());
+                Ok(self)
+            }
+            "--eol" => {
+                self.options.eol = match value {
+                    "lf" => Some(LineEnding::Lf),
+                    "crlf" => Some(LineEnding::CrLf),
+                    "keep" => None,
+                    _ => return Err(ArgsError::InvalidValue(flag.to_string(), value.to_string())),
+                };
+                Ok(self)
+            }
+            "--max-filesize" => {
+                let size = parse_size(value)
+                    .ok_or_else(|| ArgsError::InvalidValue(flag.to_string(), value.to_string()))?;
+                self.options.max_filesize = Some(size);
+                Ok(self)
+            }
+            "--binary-scan" => {
+                let size = parse_size(value)
+                    .and_then(|s| usize::try_from(s).ok())
+                    .ok_or_else(|| ArgsError::InvalidValue(flag.to_string(), value.to_string()))?;
+                self.options.binary_scan = size;
+                Ok(self)
+            }
+            "--encoding" | "-E" => {
+                let encoding = encoding_for_label(value)
+                    .ok_or_else(|| ArgsError::InvalidValue(flag.to_string(), value.to_string()))?;
+                self.options.encoding = Some(encoding);
+                Ok(self)
+            }
+            "--file" | "-f" => {
+                self.rule_sources.push(RuleSource::File(value.to_string()));
+                Ok(self)
+            }
+            "--regexp" | "-e" => {
+                self.rule_sources.push(RuleSource::Expression(value.to_string(), None));
+                Ok(self)
+            }
+            "--replacement" | "-r" => match self.rule_sources.last_mut() {
+                Some(RuleSource::Expression(_, replacement @ None)) => {
+                    *replacement = Some(value.to_string());
+                    Ok(self)
+                }
+                _ => Err(ArgsError::UnpairedReplacement(value.to_string())),
+            },
+            "--backup" if !value.is_empty() && !value.contains(std::path::is_separator) => {
+                self.backup = Some(Some(value.to_string()));
+                Ok(self)
+            }
+            "--backup" => Err(ArgsError::InvalidValue(flag.to_string(), value.to_string())),
+            _ => Err(ArgsError::UnrecognizedArgument(flag.to_string())),
+        }
+    }
+
     fn handle_flag(&mut self, arg: &str) -> Result<&mut IncompleteArgs, ArgsError> {
+        if let Some((flag, value)) = arg.split_once('=') {
+            // `--backup` is the one flag whose value is optional, so it can only be given with `=`.
+            if flag_takes_value(flag) || flag == "--backup" {
+                return self.handle_flag_value(flag, value);
+            }
+        }
+
+        if flag_takes_value(arg) {
+            self.pending_flag = Some(arg.to_string());
+            return Ok(self);
+        }
+
         match arg {
             "--help" | "-h" => {
                 print_help();
@@ -89,6 +335,82 @@ impl IncompleteArgs {
                 self.mode = FarMode::Lines;
                 Ok(self)
             }
+            "--dry-run" | "-n" => {
+                self.options.dry_run = true;
+                Ok(self)
+            }
+            "--diff" | "-d" => {
+                self.options.diff = true;
+                Ok(self)
+            }
+            "--interactive" | "-p" => {
+                self.options.interactive = true;
+                Ok(self)
+            }
+            "--atomic-run" => {
+                self.options.atomic_run = true;
+                Ok(self)
+            }
+            "--simultaneous" => {
+                self.options.simultaneous = true;
+                Ok(self)
+            }
+            "--stats" => {
+                self.options.stats = true;
+                Ok(self)
+            }
+            "--hidden" | "-." => {
+                self.options.walk.hidden = true;
+                Ok(self)
+            }
+            "--no-ignore" => {
+                self.options.walk.ignore = false;
+                Ok(self)
+            }
+            "--follow" | "-L" => {
+                self.options.walk.follow = true;
+                Ok(self)
+            }
+            "--fixed-strings" | "-F" => {
+                self.pattern_options.fixed_strings = true;
+                Ok(self)
+            }
+            "--ignore-case" | "-i" => {
+                self.pattern_options.case = CaseMode::Insensitive;
+                Ok(self)
+            }
+            "--smart-case" | "-S" => {
+                self.pattern_options.case = CaseMode::Smart;
+                Ok(self)
+            }
+            "--word-regexp" | "-w" => {
+                self.pattern_options.whole_words = true;
+                Ok(self)
+            }
+            "--preserve-case" => {
+                self.pattern_options.preserve_case = true;
+                Ok(self)
+            }
+            "--binary" => {
+                self.pattern_options.binary = true;
+                Ok(self)
+            }
+            "--type-list" => {
+                self.type_list = true;
+                Ok(self)
+            }
+            "--recover" => {
+                self.recover = true;
+                Ok(self)
+            }
+            "--backup" => {
+                self.backup = Some(None);
+                Ok(self)
+            }
+            "--undo" => {
+                self.undo = true;
+                Ok(self)
+            }
             "--" => {
                 self.process_flags = false;
                 Ok(self)
@@ -97,47 +419,129 @@ impl IncompleteArgs {
         }
     }
 
-    fn handle_argument(&mut self, arg: &str) -> Result<&mut IncompleteArgs, ArgsError> {
-        if self.process_flags && arg.starts_with("-") {
-            self.handle_flag(arg)
+    fn handle_argument(&mut self, arg: &OsStr) -> Result<&mut IncompleteArgs, ArgsError> {
+        if let Some(flag) = self.pending_flag.take() {
+            self.handle_flag_value(&flag, to_str(arg)?)
+        } else if self.process_flags && arg.to_string_lossy().starts_with('-') {
+            self.handle_flag(to_str(arg)?)
         } else {
             self.handle_positional(arg)
         }
     }
 
-    fn to_args(mut self) -> Result<Args, ArgsError> {
-        if self.paths.is_empty() {
-            self.paths = vec![".".to_string()]
+    fn into_command(self) -> Result<Command, ArgsError> {
+        if let Some(flag) = self.pending_flag {
+            return Err(ArgsError::MissingValue(flag));
+        }
+
+        build_types(&self.options.walk.type_defs, &self.options.walk.types)
+            .map_err(ArgsError::InvalidFileType)?;
+
+        if self.undo {
+            // Undo only ever restores the last run, so anything saying what to replace or where is a mistake.
+            if let Some(source) = self.rule_sources.first() {
+                return Err(ArgsError::IncompatibleFlags("--undo", source.flag()));
+            }
+            if self.recover {
+                return Err(ArgsError::IncompatibleFlags("--undo", "--recover"));
+            }
+            if let Some(arg) = self.positionals.first() {
+                return Err(ArgsError::UnrecognizedArgument(arg.to_string_lossy().into_owned()));
+            }
+            return journal_dir().map(Command::Undo).ok_or(ArgsError::NoJournalDir);
+        }
+
+        let mut positionals = self.positionals.into_iter();
+
+        let (pattern, replacement) = match self.recover || !self.rule_sources.is_empty() {
+            true => (None, None),
+            false => (positionals.next(), positionals.next()),
+        };
+
+        let mut paths: Vec<OsString> = positionals.collect();
+        if paths.is_empty() {
+            paths = vec![OsString::from(".")]
+        }
+
+        if self.recover {
+            return Ok(Command::Recover(paths, self.options.walk));
         }
 
-        match (self.pattern, self.replacement, &self.paths[..]) {
-            (None, _, _) => Err(ArgsError::NoArgsGiven),
-            (_, None, _) => Err(ArgsError::OnlyPatternGiven),
-            (Some(pat), Some(repl), _) => Ok(Args {
-                pattern: pat,
-                replacement: repl,
-                paths: self.paths,
-                mode: self.mode,
-            }),
+        let mut options = self.options;
+        if let Some(suffix) = self.backup {
+            let journal = journal_dir().ok_or(ArgsError::NoJournalDir)?;
+            options.backup = Some(Backup::new(&journal, suffix));
+        }
+
+        if self.pattern_options.preserve_case && self.pattern_options.binary {
+            return Err(ArgsError::IncompatibleFlags("--preserve-case", "--binary"));
+        }
+        if let Some(flag) = self.pattern_options.preserve_case_conflict() {
+            return Err(ArgsError::IncompatibleFlags("--preserve-case", flag));
+        }
+        if options.interactive && self.pattern_options.binary {
+            return Err(ArgsError::IncompatibleFlags("--interactive", "--binary"));
+        }
+
+        if let Some(source) = self.rule_sources.first() {
+            let flag = source.flag();
+            if self.pattern_options.binary {
+                return Err(ArgsError::IncompatibleFlags(flag, "--binary"));
+            }
+            if options.interactive {
+                return Err(ArgsError::IncompatibleFlags("--interactive", flag));
+            }
+
+            let rules = build_rules(&self.rule_sources, self.mode, &self.pattern_options)?;
+            return Ok(Command::Replace(Box::new(Args { rules, paths, options })));
+        }
+
+        match (pattern, replacement) {
+            (None, _) => Err(ArgsError::NoArgsGiven),
+            (Some(pat), None) => {
+                Pattern::new(to_str(&pat)?, &self.pattern_options)?;
+                Err(ArgsError::OnlyPatternGiven)
+            }
+            (Some(pat), Some(repl)) => Ok(Command::Replace(Box::new(Args {
+                rules: vec![Rule::new(to_str(&pat)?, to_str(&repl)?, self.mode, &self.pattern_options)?],
+                paths,
+                options,
+            }))),
         }
     }
 }
 
-pub fn parse_cmdline() -> Result<Args, ArgsError> {
-    parse_args(env::args().skip(1))
+pub fn parse_cmdline() -> Result<Command, ArgsError> {
+    parse_command(env::args_os().skip(1))
 }
 
-pub fn parse_args<S, I>(args: I) -> Result<Args, ArgsError>
+pub fn parse_command<S, I>(args: I) -> Result<Command, ArgsError>
 where
-    S: AsRef<str>,
+    S: AsRef<OsStr>,
     I: IntoIterator<Item = S>,
 {
     let mut ia = IncompleteArgs::new();
-    let _ = args
-        .into_iter()
-        .fold(Ok(&mut ia), |a, c| a.and_then(|ia| ia.handle_argument(c.as_ref())))?;
+    args.into_iter()
+        .try_fold(&mut ia, |ia, c| ia.handle_argument(c.as_ref()))?;
+
+    if ia.type_list {
+        print_type_list(&ia.options.walk.type_defs)?;
+        std::process::exit(0)
+    }
 
-    Ok(ia.to_args()?)
+    ia.into_command()
+}
+
+#[cfg(test)]
+pub fn parse_args<S, I>(args: I) -> Result<Args, ArgsError>
+where
+    S: AsRef<OsStr>,
+    I: IntoIterator<Item = S>,
+{
+    match parse_command(args)? {
+        Command::Replace(args) => Ok(*args),
+        c => panic!("Expected a replacement, got {:?}", c),
+    }
 }
 
 fn prog_name() -> String {
@@ -149,12 +553,66 @@ pub fn print_usage() {
         "usage: {} [flag...] pattern replacement [path...]",
         prog_name()
     );
+    println!("       {} [flag...] (-e pattern -r replacement | -f path)... [path...]", prog_name());
+    println!("       {} --recover [flag...] [path...]", prog_name());
     println!();
     println!("flags:");
     println!("  -h, --help:       display the help");
     println!("  -m, --multiline:  match the whole file instead of line-by-line");
     println!("  -s, --singleline: match line-by-line. this is the default");
+    println!("  -n, --dry-run:    report the matches in each file without changing anything");
+    println!("  -d, --diff:       print a unified diff of every file that changes");
+    println!("  -p, --interactive: show each match with the lines around it and ask whether to replace it");
+    println!("      --atomic-run: write every changed file aside first, and only swap them in if none of them failed");
+    println!("      --backup[=suffix]: keep a copy of every file that's changed, next to it with the suffix if one is given");
+    println!("                    and in the journal otherwise. either way the run can be undone with --undo. files");
+    println!("                    ending in the suffix are left alone, so a run doesn't replace in its own backups");
+    println!("      --undo:       restore the files changed by the last run made with --backup, unless they've changed");
+    println!("                    since. the journal is kept in $FAR_JOURNAL_DIR, $XDG_STATE_HOME/far or ~/.local/state/far");
+    println!("      --stats:      print a breakdown of the files scanned, changed and skipped instead of a one-line summary");
+    println!("      --eol lf|crlf|keep: rewrite every line ending as lf or crlf. the default keeps each line's own");
+    println!("      --max-filesize size: skip files bigger than the size, e.g. 512K, 10M or 2G");
+    println!("  -f, --file path:  read rules like s/pattern/replacement/flags from the file, one per line, and apply them in order.");
+    println!("                    the flags are i, S, F, w and P like the flags above, and m for --multiline. every");
+    println!("                    positional argument is then a path. can be given more than once");
+    println!("  -e, --regexp pattern -r, --replacement replacement: a pattern and its replacement, as a pair. can be");
+    println!("                    given more than once, and mixed with --file. every positional argument is then a path");
+    println!("      --simultaneous: apply every rule from -e and --file in a single pass instead of one after the other,");
+    println!("                    so no text is replaced twice and e.g. -e a -r b -e b -r a swaps the two");
+    println!("  -F, --fixed-strings: treat the pattern and the replacement as literal text instead of a regex");
+    println!("  -i, --ignore-case: match regardless of case");
+    println!("  -S, --smart-case: match regardless of case, unless the pattern has an uppercase letter in it");
+    println!("  -w, --word-regexp: only match whole words, i.e. where the match isn't next to a letter, digit or underscore");
+    println!("      --preserve-case: also replace the snake_case, camelCase, PascalCase, kebab-case and SCREAMING_CASE");
+    println!("                    forms of the pattern with the same form of the replacement. both are taken literally");
+    println!("      --binary:     match raw bytes, so files that look binary or aren't utf-8 can be changed too.");
+    println!("                    \\xNN in the replacement writes the byte NN");
+    println!("      --binary-scan size: treat files with a nul byte in their first size bytes as binary. 0 turns this off");
+    println!("  -E, --encoding name: decode files without a byte order mark as e.g. latin1 or utf-16le. the default is utf-8");
+    println!("  -., --hidden:     include hidden files and directories");
+    println!("      --no-ignore:  don't respect .gitignore, .ignore and .git/info/exclude");
+    println!("  -L, --follow:     follow symlinks. replacements in linked files are written to their targets");
+    println!("      --include glob: only search files matching the glob. can be given more than once");
+    println!("      --exclude glob: skip files and directories matching the glob. can be given more than once");
+    println!("  -t, --type name:  only search files of the given type. can be given more than once");
+    println!("  -T, --type-not name: skip files of the given type. can be given more than once");
+    println!("      --type-add name:glob: add a glob to a file type, creating it if needed");
+    println!("      --type-list:  list the known file types and exit");
+    println!("      --recover:    clean up the temp files left behind by an interrupted run");
     println!();
+    println!("exit status: 0 if anything matched, 1 if nothing did, 2 if the arguments are invalid, and 3 if any file or");
+    println!("path couldn't be read or written");
+    println!();
+}
+
+pub fn print_type_list<S: AsRef<str>>(type_defs: &[S]) -> Result<(), ArgsError> {
+    let types = build_types(type_defs, &[]).map_err(ArgsError::InvalidFileType)?;
+
+    for def in types.definitions() {
+        println!("{}: {}", def.name(), def.globs().join(", "));
+    }
+
+    Ok(())
 }
 
 pub fn print_help() {
@@ -174,11 +632,11 @@ mod tests {
         let args = parse_args(cmdline)?;
 
         assert_eq!(args.paths, vec!["/tmp"]);
-        debug_assert!(args.pattern.is_match("abc").unwrap());
-        debug_assert!(!args.pattern.is_match("ab").unwrap());
-        debug_assert!(args.pattern.is_match("abcd").unwrap());
-        assert_eq!(args.replacement, "def");
-        assert_eq!(args.mode, FarMode::Lines);
+        debug_assert!(args.rules[0].pattern.is_match("abc"));
+        debug_assert!(!args.rules[0].pattern.is_match("ab"));
+        debug_assert!(args.rules[0].pattern.is_match("abcd"));
+        assert_eq!(args.rules[0].replacement, "def");
+        assert_eq!(args.rules[0].mode, FarMode::Lines);
 
         Ok(())
     }
@@ -189,11 +647,11 @@ mod tests {
         let args = parse_args(cmdline)?;
 
         assert_eq!(args.paths, vec!["."]);
-        debug_assert!(args.pattern.is_match("abc").unwrap());
-        debug_assert!(!args.pattern.is_match("ab").unwrap());
-        debug_assert!(args.pattern.is_match("abcd").unwrap());
-        assert_eq!(args.replacement, "def");
-        assert_eq!(args.mode, FarMode::Lines);
+        debug_assert!(args.rules[0].pattern.is_match("abc"));
+        debug_assert!(!args.rules[0].pattern.is_match("ab"));
+        debug_assert!(args.rules[0].pattern.is_match("abcd"));
+        assert_eq!(args.rules[0].replacement, "def");
+        assert_eq!(args.rules[0].mode, FarMode::Lines);
 
         Ok(())
     }
@@ -204,11 +662,11 @@ mod tests {
         let args = parse_args(cmdline)?;
 
         assert_eq!(args.paths, vec!["/tmp", "/var/tmp"]);
-        debug_assert!(args.pattern.is_match("abc").unwrap());
-        debug_assert!(!args.pattern.is_match("ab").unwrap());
-        debug_assert!(args.pattern.is_match("abcd").unwrap());
-        assert_eq!(args.replacement, "def");
-        assert_eq!(args.mode, FarMode::Lines);
+        debug_assert!(args.rules[0].pattern.is_match("abc"));
+        debug_assert!(!args.rules[0].pattern.is_match("ab"));
+        debug_assert!(args.rules[0].pattern.is_match("abcd"));
+        assert_eq!(args.rules[0].replacement, "def");
+        assert_eq!(args.rules[0].mode, FarMode::Lines);
 
         Ok(())
     }
@@ -253,11 +711,11 @@ mod tests {
         let args = parse_args(cmdline)?;
 
         assert_eq!(args.paths, vec!["."]);
-        debug_assert!(args.pattern.is_match("abc").unwrap());
-        debug_assert!(!args.pattern.is_match("ab").unwrap());
-        debug_assert!(args.pattern.is_match("abcd").unwrap());
-        assert_eq!(args.replacement, "def");
-        assert_eq!(args.mode, FarMode::All);
+        debug_assert!(args.rules[0].pattern.is_match("abc"));
+        debug_assert!(!args.rules[0].pattern.is_match("ab"));
+        debug_assert!(args.rules[0].pattern.is_match("abcd"));
+        assert_eq!(args.rules[0].replacement, "def");
+        assert_eq!(args.rules[0].mode, FarMode::All);
 
         Ok(())
     }
@@ -268,11 +726,371 @@ mod tests {
         let args = parse_args(cmdline)?;
 
         assert_eq!(args.paths, vec!["--multiline"]);
-        debug_assert!(args.pattern.is_match("abc").unwrap());
-        debug_assert!(!args.pattern.is_match("ab").unwrap());
-        debug_assert!(args.pattern.is_match("abcd").unwrap());
-        assert_eq!(args.replacement, "def");
-        assert_eq!(args.mode, FarMode::Lines);
+        debug_assert!(args.rules[0].pattern.is_match("abc"));
+        debug_assert!(!args.rules[0].pattern.is_match("ab"));
+        debug_assert!(args.rules[0].pattern.is_match("abcd"));
+        assert_eq!(args.rules[0].replacement, "def");
+        assert_eq!(args.rules[0].mode, FarMode::Lines);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_args_dry_run() -> Result<(), ArgsError> {
+        let cmdline = "-n abc def".split(char::is_whitespace);
+        let args = parse_args(cmdline)?;
+
+        assert_eq!(args.paths, vec!["."]);
+        assert_eq!(args.rules[0].replacement, "def");
+        debug_assert!(args.options.dry_run);
+
+        let args = parse_args("abc def".split(char::is_whitespace))?;
+        debug_assert!(!args.options.dry_run);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_args_walk_options() -> Result<(), ArgsError> {
+        let args = parse_args("abc def".split(char::is_whitespace))?;
+        assert_eq!(args.options.walk, WalkOptions::default());
+
+        let args = parse_args("--hidden --no-ignore -L abc def".split(char::is_whitespace))?;
+        debug_assert!(args.options.walk.hidden);
+        debug_assert!(!args.options.walk.ignore);
+        debug_assert!(args.options.walk.follow);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_args_globs() -> Result<(), ArgsError> {
+        let cmdline = "--include *.rs abc def --exclude=vendor/** --include=*.toml".split(char::is_whitespace);
+        let args = parse_args(cmdline)?;
+
+        assert_eq!(args.options.walk.globs, vec!["*.rs", "!vendor/**", "*.toml"]);
+        assert_eq!(args.rules[0].replacement, "def");
+        assert_eq!(args.paths, vec!["."]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_args_file_types() -> Result<(), ArgsError> {
+        let cmdline = "-t rust --type-not=yaml --type-add conf:*.conf --type conf abc def".split(char::is_whitespace);
+        let args = parse_args(cmdline)?;
+
+        assert_eq!(args.options.walk.types, vec![
+            TypeSelection::Select("rust".to_string()),
+            TypeSelection::Negate("yaml".to_string()),
+            TypeSelection::Select("conf".to_string()),
+        ]);
+        assert_eq!(args.options.walk.type_defs, vec!["conf:*.conf"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_args_rejects_unknown_file_type() {
+        let args_err = parse_args(vec!["abc", "def", "--type", "notatype"]).unwrap_err();
+
+        match args_err {
+            ArgsError::InvalidFileType(_) => {},
+            _ => panic!("The error should be for an invalid file type.")
+        }
+    }
+
+    #[test]
+    fn test_args_rejects_missing_flag_value() {
+        let args_err = parse_args(vec!["abc", "def", "--include"]).unwrap_err();
+
+        match args_err {
+            ArgsError::MissingValue(s) => assert_eq!(s, "--include"),
+            _ => panic!("The error should be for a missing value.")
+        }
+    }
+
+    #[test]
+    fn test_args_rejects_invalid_glob() {
+        let args_err = parse_args(vec!["abc", "def", "--include", "a[b"]).unwrap_err();
+
+        match args_err {
+            ArgsError::InvalidGlob(_) => {},
+            _ => panic!("The error should be for an invalid glob.")
+        }
+    }
+
+    #[test]
+    fn test_args_recover() -> Result<(), ArgsError> {
+        match parse_command("--recover a(b /tmp".split(char::is_whitespace))? {
+            Command::Recover(paths, walk) => {
+                assert_eq!(paths, vec!["a(b", "/tmp"]);
+                assert_eq!(walk, WalkOptions::default());
+            }
+            c => panic!("Expected a recovery, got {:?}", c),
+        };
+
+        match parse_command(vec!["--recover", "--hidden"])? {
+            Command::Recover(paths, walk) => {
+                assert_eq!(paths, vec!["."]);
+                debug_assert!(walk.hidden);
+            }
+            c => panic!("Expected a recovery, got {:?}", c),
+        };
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_args_non_utf8_paths() -> Result<(), ArgsError> {
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = OsStr::from_bytes(b"caf\xe9");
+        let args = parse_args(vec![OsStr::new("abc"), OsStr::new("def"), path])?;
+        assert_eq!(args.paths, vec![path]);
+
+        match parse_args(vec![path, OsStr::new("def")]) {
+            Err(ArgsError::NotUnicode(s)) => assert_eq!(s, path),
+            _ => panic!("The error should be for a non-unicode pattern."),
+        };
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_args_diff() -> Result<(), ArgsError> {
+        let args = parse_args("--diff abc def -n".split(char::is_whitespace))?;
+
+        debug_assert!(args.options.diff);
+        debug_assert!(args.options.dry_run);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_args_interactive() -> Result<(), ArgsError> {
+        debug_assert!(parse_args("-p abc def".split(char::is_whitespace))?.options.interactive);
+        debug_assert!(matches!(
+            parse_args("--interactive --binary abc def".split(char::is_whitespace)),
+            Err(ArgsError::IncompatibleFlags("--interactive", "--binary"))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_args_backup() -> Result<(), ArgsError> {
+        let backup = |cmdline: &str| parse_args(cmdline.split(char::is_whitespace)).map(|a| a.options.backup);
+
+        debug_assert_eq!(backup("abc def")?, None);
+        debug_assert_eq!(backup("--backup abc def")?.map(|b| b.suffix), Some(None));
+        debug_assert_eq!(backup("--backup=.bak abc def")?.map(|b| b.suffix), Some(Some(".bak".to_string())));
+        debug_assert!(matches!(backup("--backup= abc def"), Err(ArgsError::InvalidValue(_, _))));
+        debug_assert!(matches!(parse_command(vec!["--undo"])?, Command::Undo(_)));
+        debug_assert!(matches!(parse_command(vec!["--undo", "abc", "def"]), Err(ArgsError::UnrecognizedArgument(_))));
+        debug_assert!(matches!(
+            parse_command(vec!["--undo", "-e", "abc", "-r", "def"]),
+            Err(ArgsError::IncompatibleFlags("--undo", "--regexp"))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_args_atomic_run() -> Result<(), ArgsError> {
+        debug_assert!(parse_args("--atomic-run abc def".split(char::is_whitespace))?.options.atomic_run);
+        debug_assert!(!parse_args("abc def".split(char::is_whitespace))?.options.atomic_run);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_args_rules_file() -> Result<(), ArgsError> {
+        let rules = crate::testdir::testdir::TestFile::new("s/abc/def/\ns/x/y/i\n");
+        let path = rules.path_str();
+
+        let args = parse_args(vec!["-f", &path, "-m", "src"])?;
+        assert_eq!(args.rules.len(), 2);
+        assert_eq!(args.rules[1].mode, FarMode::All);
+        assert_eq!(args.paths, vec!["src"]);
+        debug_assert!(args.rules[1].pattern.is_match("X"));
+
+        debug_assert!(matches!(
+            parse_args(vec!["--binary", "-f", &path]),
+            Err(ArgsError::IncompatibleFlags("--file", "--binary"))
+        ));
+        debug_assert!(matches!(parse_args(vec!["-f", "/nonexistent/rules"]), Err(ArgsError::InvalidRules(_, _))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_args_expressions() -> Result<(), ArgsError> {
+        let rules = crate::testdir::testdir::TestFile::new("s/x/y/\n");
+        let path = rules.path_str();
+
+        let args = parse_args(vec!["-e", "a", "-r", "b", "-f", &path, "--regexp=b", "--replacement=a", "--simultaneous", "src"])?;
+        assert_eq!(args.rules.len(), 3);
+        assert_eq!(args.rules[2].replacement, "a");
+        assert_eq!(args.paths, vec!["src"]);
+        debug_assert!(args.rules[1].pattern.is_match("x"));
+        debug_assert!(args.options.simultaneous);
+
+        debug_assert!(matches!(parse_args(vec!["-e", "a", "src"]), Err(ArgsError::OnlyPatternGiven)));
+        debug_assert!(matches!(parse_args(vec!["-r", "b"]), Err(ArgsError::UnpairedReplacement(_))));
+        debug_assert!(matches!(parse_args(vec!["-e", "a", "-r", "b", "-r", "c"]), Err(ArgsError::UnpairedReplacement(_))));
+        debug_assert!(matches!(
+            parse_args(vec!["-p", "-e", "a", "-r", "b"]),
+            Err(ArgsError::IncompatibleFlags("--interactive", "--regexp"))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_args_stats() -> Result<(), ArgsError> {
+        debug_assert!(parse_args("--stats abc def".split(char::is_whitespace))?.options.stats);
+        debug_assert!(!parse_args("abc def".split(char::is_whitespace))?.options.stats);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_args_eol() -> Result<(), ArgsError> {
+        assert_eq!(parse_args("abc def".split(char::is_whitespace))?.options.eol, None);
+        assert_eq!(parse_args("--eol crlf abc def".split(char::is_whitespace))?.options.eol, Some(LineEnding::CrLf));
+        assert_eq!(parse_args("abc def --eol=lf".split(char::is_whitespace))?.options.eol, Some(LineEnding::Lf));
+        assert_eq!(parse_args("--eol=crlf --eol=keep abc def".split(char::is_whitespace))?.options.eol, None);
+
+        match parse_args(vec!["--eol", "cr", "abc", "def"]).unwrap_err() {
+            ArgsError::InvalidValue(flag, value) => assert_eq!((flag.as_str(), value.as_str()), ("--eol", "cr")),
+            _ => panic!("The error should be for an invalid value."),
+        };
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_args_max_filesize() -> Result<(), ArgsError> {
+        assert_eq!(parse_args("abc def".split(char::is_whitespace))?.options.max_filesize, None);
+        assert_eq!(parse_args("--max-filesize 512 abc def".split(char::is_whitespace))?.options.max_filesize, Some(512));
+        assert_eq!(parse_args("--max-filesize=10M abc def".split(char::is_whitespace))?.options.max_filesize, Some(10 << 20));
+
+        for value in ["", "G", "1.5M", "-1", "1T"] {
+            match parse_args(vec!["--max-filesize", value, "abc", "def"]) {
+                Err(ArgsError::InvalidValue(_, v)) => assert_eq!(v, value),
+                _ => panic!("The error should be for an invalid value."),
+            };
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_args_binary() -> Result<(), ArgsError> {
+        let args = parse_args("abc def".split(char::is_whitespace))?;
+        debug_assert!(matches!(args.rules[0].pattern, Pattern::Text(_)));
+        assert_eq!(args.options.binary_scan, DEFAULT_BINARY_SCAN);
+
+        let args = parse_args("--binary caf\\xe9 def --binary-scan=0".split(char::is_whitespace))?;
+        debug_assert!(matches!(args.rules[0].pattern, Pattern::Bytes(_)));
+        assert_eq!(args.options.binary_scan, 0);
+
+        match parse_args(vec!["--binary", "(?=a)", "def"]).unwrap_err() {
+            ArgsError::InvalidBinaryRegex(_) => {}
+            _ => panic!("Lookaround should be rejected in binary mode."),
+        };
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_args_encoding() -> Result<(), ArgsError> {
+        assert_eq!(parse_args("abc def".split(char::is_whitespace))?.options.encoding, None);
+
+        let args = parse_args("-E latin1 abc def".split(char::is_whitespace))?;
+        assert_eq!(args.options.encoding, Some(encoding_rs::WINDOWS_1252));
+
+        let args = parse_args("--encoding=UTF-16LE abc def".split(char::is_whitespace))?;
+        assert_eq!(args.options.encoding, Some(encoding_rs::UTF_16LE));
+
+        match parse_args(vec!["--encoding", "klingon", "abc", "def"]).unwrap_err() {
+            ArgsError::InvalidValue(flag, _) => assert_eq!(flag, "--encoding"),
+            _ => panic!("The error should be for an invalid value."),
+        };
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_args_fixed_strings() -> Result<(), ArgsError> {
+        let args = parse_args(vec!["-F", "foo.bar()", "$1"])?;
+        debug_assert!(matches!(args.rules[0].pattern, Pattern::Text(crate::pattern::TextPattern::Literal(_))));
+        debug_assert!(args.rules[0].pattern.is_match("a foo.bar() b"));
+        debug_assert!(!args.rules[0].pattern.is_match("fooxbar"));
+        assert_eq!(args.rules[0].replacement, "$1");
+
+        let args = parse_args(vec!["--fixed-strings", "--binary", "a.b", "$1"])?;
+        debug_assert!(args.rules[0].pattern.is_match("a.b"));
+        debug_assert!(!args.rules[0].pattern.is_match("axb"));
+        assert_eq!(args.rules[0].replacement, "$$1");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_args_case() -> Result<(), ArgsError> {
+        let args = parse_args("-i user def".split(char::is_whitespace))?;
+        debug_assert!(args.rules[0].pattern.is_match("USER"));
+
+        let args = parse_args("-S -F User.Id def".split(char::is_whitespace))?;
+        debug_assert!(args.rules[0].pattern.is_match("User.Id"));
+        debug_assert!(!args.rules[0].pattern.is_match("user.id"));
+
+        let args = parse_args("-i -S user def".split(char::is_whitespace))?;
+        debug_assert!(args.rules[0].pattern.is_match("User"));
+
+        let args = parse_args("-S -i User def".split(char::is_whitespace))?;
+        debug_assert!(args.rules[0].pattern.is_match("user"));
+
+        let args = parse_args("User def".split(char::is_whitespace))?;
+        debug_assert!(!args.rules[0].pattern.is_match("user"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_args_preserve_case() -> Result<(), ArgsError> {
+        let args = parse_args("--preserve-case user_id account_id".split(char::is_whitespace))?;
+        debug_assert!(args.rules[0].pattern.is_match("UserId"));
+        debug_assert!(args.rules[0].pattern.is_match("USER_ID"));
+        debug_assert!(!args.rules[0].pattern.is_match("user.id"));
+
+        match parse_args("--preserve-case --binary a b".split(char::is_whitespace)).unwrap_err() {
+            ArgsError::IncompatibleFlags(_, _) => {}
+            _ => panic!("The error should be for incompatible flags."),
+        };
+
+        for (flag, long) in [("-i", "--ignore-case"), ("-S", "--smart-case"), ("-F", "--fixed-strings")] {
+            debug_assert!(matches!(
+                parse_args(vec!["--preserve-case", flag, "a", "b"]),
+                Err(ArgsError::IncompatibleFlags("--preserve-case", f)) if f == long
+            ));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_args_word_regexp() -> Result<(), ArgsError> {
+        let args = parse_args("-w id key".split(char::is_whitespace))?;
+        debug_assert!(args.rules[0].pattern.is_match("x.id"));
+        debug_assert!(!args.rules[0].pattern.is_match("width"));
+
+        let args = parse_args("-w --preserve-case id key".split(char::is_whitespace))?;
+        debug_assert!(args.rules[0].pattern.is_match("ID"));
+        debug_assert!(!args.rules[0].pattern.is_match("valid"));
 
         Ok(())
     }
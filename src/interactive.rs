@@ -0,0 +1,167 @@
+use crate::iter::lines::{count_line_endings, split_lines};
+use std::io::{BufRead, Write};
+use std::ops::Range;
+use std::path::Path;
+
+/// How many lines are shown above and below the lines a match is on.
+const CONTEXT_LINES: usize = 2;
+
+const HELP: &str = "\
+y - replace this match
+n - leave this match alone
+a - replace this and every other match in this file
+q - quit, leaving this and every later match alone
+e - type the replacement for this match
+? - print this help
+";
+
+/// What to do with a single match in `--interactive` mode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision {
+    Yes,
+    No,
+    /// Replace this and every later match in the same file without asking.
+    All,
+    /// Leave this and every later match alone, in this file and every other one.
+    Quit,
+    /// Replace this match with the given text instead, taken literally.
+    Edit(String),
+}
+
+fn push_prefixed(out: &mut String, prefix: &str, lines: &str) {
+    for (line, _) in split_lines(lines) {
+        out.push_str(prefix);
+        out.push_str(line);
+        out.push('\n');
+    }
+}
+
+/// Shows the lines `range` of `text` is on as they are and as they would be with `replacement`,
+/// along with a few lines around them.
+fn preview(text: &str, range: &Range<usize>, replacement: &str) -> String {
+    let is_eol = |c| c == '\n' || c == '\r';
+    let line_start = text[..range.start].rfind(is_eol).map_or(0, |i| i + 1);
+    let line_end = text[range.end..].find(is_eol).map_or(text.len(), |i| range.end + i);
+
+    let before: Vec<&str> = split_lines(&text[..line_start]).map(|(l, _)| l).collect();
+    let after = split_lines(&text[line_end..]).skip(1).take(CONTEXT_LINES).map(|(l, _)| l);
+    let new = format!("{}{}{}", &text[line_start..range.start], replacement, &text[range.end..line_end]);
+
+    let mut out = String::new();
+    for line in &before[before.len().saturating_sub(CONTEXT_LINES)..] {
+        push_prefixed(&mut out, "  ", line);
+    }
+    push_prefixed(&mut out, "- ", &text[line_start..line_end]);
+    push_prefixed(&mut out, "+ ", &new);
+    for line in after {
+        push_prefixed(&mut out, "  ", line);
+    }
+
+    out
+}
+
+/// Asks about each match in turn, reading the answers from `input`. Running out of input counts as quitting.
+pub struct Prompter<R: BufRead, W: Write> {
+    input: R,
+    output: W,
+    quit: bool,
+}
+
+impl<R: BufRead, W: Write> Prompter<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        Prompter {
+            input,
+            output,
+            quit: false,
+        }
+    }
+
+    /// Whether the user has quit, so no more files should be looked at.
+    pub fn quit(&self) -> bool {
+        self.quit
+    }
+
+    fn read_line(&mut self, prompt: &str) -> Option<String> {
+        let _ = write!(self.output, "{}", prompt);
+        let _ = self.output.flush();
+
+        let mut line = String::new();
+        match self.input.read_line(&mut line) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(line.trim_end_matches(['\n', '\r']).to_string()),
+        }
+    }
+
+    pub fn ask(&mut self, path: &Path, text: &str, range: Range<usize>, replacement: &str) -> Decision {
+        let line = count_line_endings(&text.as_bytes()[..range.start]) + 1;
+        let _ = write!(self.output, "{}:{}\n{}", path.display(), line, preview(text, &range, replacement));
+
+        let decision = loop {
+            let answer = match self.read_line("Replace this match? [y,n,a,q,e,?] ") {
+                Some(answer) => answer,
+                None => break Decision::Quit,
+            };
+
+            match answer.trim() {
+                "y" => break Decision::Yes,
+                "n" => break Decision::No,
+                "a" => break Decision::All,
+                "q" => break Decision::Quit,
+                "e" => match self.read_line("Replacement: ") {
+                    Some(replacement) => break Decision::Edit(replacement),
+                    None => break Decision::Quit,
+                },
+                _ => {
+                    let _ = write!(self.output, "{}", HELP);
+                }
+            }
+        };
+
+        self.quit = decision == Decision::Quit;
+        decision
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_preview() {
+        let text = "a\nb\nc\nfoo(x)\nd\ne\nf\n";
+        let start = text.find("foo").unwrap();
+
+        debug_assert_eq!(
+            preview(text, &(start..start + 3), "bar"),
+            "  b\n  c\n- foo(x)\n+ bar(x)\n  d\n  e\n"
+        );
+        debug_assert_eq!(preview("foo\r\nbar", &(0..8), "baz"), "- foo\n- bar\n+ baz\n");
+    }
+
+    #[test]
+    fn test_prompter() {
+        let input = "?\ny\nn\ne\nnew\na\nq\n";
+        let mut output = Vec::new();
+        let mut prompter = Prompter::new(input.as_bytes(), &mut output);
+        let path = Path::new("file");
+
+        let decisions: Vec<Decision> = (0..6).map(|_| prompter.ask(path, "abc", 0..1, "x")).collect();
+
+        debug_assert_eq!(
+            decisions,
+            vec![
+                Decision::Yes,
+                Decision::No,
+                Decision::Edit("new".to_string()),
+                Decision::All,
+                Decision::Quit,
+                Decision::Quit
+            ]
+        );
+        debug_assert!(prompter.quit());
+
+        let output = String::from_utf8(output).unwrap();
+        debug_assert!(output.starts_with("file:1\n- abc\n+ xbc\nReplace this match?"));
+        debug_assert!(output.contains(HELP));
+    }
+}
@@ -35,9 +35,7 @@ impl<T, A: Iterator<Item=T>, B: IntoIterator<Item=T, IntoIter=A>, C: Iterator<It
                 Some(s) => return Some(s),
                 None => {
                     self.replace_current();
-                    if self.current.is_none() {
-                        return None;
-                    }
+                    self.current.as_ref()?;
                 }
             }
         }
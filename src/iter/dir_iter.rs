@@ -1,51 +1,212 @@
-use std::fs::{read_dir, DirEntry, ReadDir};
+use super::filter::{build_overrides, build_types, IgnoreStack, TypeSelection};
+use ignore::overrides::Override;
+use ignore::types::Types;
+use ignore::Match;
+use std::collections::HashSet;
+use std::fs::{self, canonicalize, read_dir, DirEntry, ReadDir};
 use std::io;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
 #[derive(Debug)]
 pub struct DirIteratorError {
-    pub path: String,
+    pub path: PathBuf,
     pub err: io::Error,
 }
 
 impl DirIteratorError {
-    pub fn new<T: ToString>(path: T, err: io::Error) -> Self {
+    pub fn new<T: AsRef<Path>>(path: T, err: io::Error) -> Self {
         DirIteratorError {
-            path: path.to_string(),
-            err: err,
+            path: path.as_ref().to_path_buf(),
+            err,
         }
     }
 }
 
 pub type Result<T> = std::result::Result<T, DirIteratorError>;
 
+/// Controls which entries a `DirIterator` skips.
+/// By default hidden files and anything matched by `.gitignore`, `.ignore` or `.git/info/exclude` are skipped.
+/// Symbolic links are skipped unless `follow` is set, in which case linked directories are traversed and linked files
+/// are yielded, so that replacements are written through to their targets.
+/// `globs` are the `--include` globs, and the `--exclude` globs prefixed with `!`, in the order they were given.
+/// `type_defs` are extra `name:glob` file types on top of the default ones, and `types` picks which of them to search.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalkOptions {
+    pub hidden: bool,
+    pub ignore: bool,
+    pub follow: bool,
+    pub globs: Vec<String>,
+    pub type_defs: Vec<String>,
+    pub types: Vec<TypeSelection>,
+}
+
+impl Default for WalkOptions {
+    fn default() -> Self {
+        WalkOptions {
+            hidden: false,
+            ignore: true,
+            follow: false,
+            globs: Vec::new(),
+            type_defs: Vec::new(),
+            types: Vec::new(),
+        }
+    }
+}
+
+fn filter_error(path: &Path, e: ignore::Error) -> DirIteratorError {
+    DirIteratorError::new(path, io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// The directories between the root of a traversal and the current one, by (device, inode).
+/// Only tracked when following symlinks, since that's the only way to walk into a loop.
+#[derive(Debug)]
+struct DirChain {
+    id: (u64, u64),
+    parent: Option<Arc<DirChain>>,
+}
+
+impl DirChain {
+    fn contains(&self, id: (u64, u64)) -> bool {
+        let mut link = Some(self);
+
+        while let Some(l) = link {
+            if l.id == id {
+                return true;
+            }
+            link = l.parent.as_deref();
+        }
+
+        false
+    }
+}
+
+/// The files yielded so far in a run, by (device, inode), shared by the iterators of every path in it.
+/// Only tracked when following symlinks, since a file and a link to it would otherwise both be replaced in.
+#[derive(Debug, Clone, Default)]
+pub struct VisitedFiles(Arc<Mutex<HashSet<(u64, u64)>>>);
+
+impl VisitedFiles {
+    /// Records the file, returning whether it's the first time it was seen.
+    fn insert(&self, id: (u64, u64)) -> bool {
+        self.0.lock().expect("The set is never left half-updated").insert(id)
+    }
+}
+
+/// The (device, inode) of what `path` resolves to.
+#[cfg(unix)]
+fn file_id(path: &Path) -> Result<Option<(u64, u64)>> {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = fs::metadata(path).into_result(path)?;
+    Ok(Some((metadata.dev(), metadata.ino())))
+}
+
+#[cfg(not(unix))]
+fn file_id(_path: &Path) -> Result<Option<(u64, u64)>> {
+    Ok(None)
+}
+
 #[derive(Debug)]
 pub struct DirIterator {
     rd: ReadDir,
-    path: String,
+    path: PathBuf,
+    abs_path: PathBuf,
+    options: WalkOptions,
+    ignores: Option<Arc<IgnoreStack>>,
+    overrides: Arc<Override>,
+    types: Arc<Types>,
+    chain: Option<Arc<DirChain>>,
+    visited: VisitedFiles,
     sub_iter: Option<Box<DirIterator>>,
 }
 
 impl DirIterator {
-    fn pathbuf_to_string(pathbuf: PathBuf) -> String {
-        pathbuf
-            .to_str()
-            .expect("What OS doesn't use unicode filenames")
-            .to_string()
-    }
-
-    pub fn new(path: &str) -> Result<DirIterator> {
-        match read_dir(path) {
-            Ok(rd) => Ok(DirIterator {
-                rd: rd,
-                path: path.to_string(),
-                sub_iter: None,
-            }),
-            Err(e) => Err(DirIteratorError::new(path, e)),
+    /// With `follow`, a file that was already yielded by another iterator sharing `visited` is skipped.
+    pub fn new<P: AsRef<Path>>(path: P, options: &WalkOptions, visited: &VisitedFiles) -> Result<DirIterator> {
+        let path = path.as_ref();
+        let rd = read_dir(path).into_result(path)?;
+        let abs_path = canonicalize(path).into_result(path)?;
+        let ignores = match options.ignore {
+            true => Some(IgnoreStack::new(&abs_path)),
+            false => None,
+        };
+        let overrides = build_overrides(&abs_path, &options.globs).map_err(|e| filter_error(path, e))?;
+        let types = build_types(&options.type_defs, &options.types).map_err(|e| filter_error(path, e))?;
+        let chain = match options.follow {
+            true => file_id(path)?.map(|id| Arc::new(DirChain { id, parent: None })),
+            false => None,
+        };
+
+        Ok(DirIterator {
+            rd,
+            path: path.to_path_buf(),
+            abs_path,
+            options: options.clone(),
+            ignores,
+            overrides: Arc::new(overrides),
+            types: Arc::new(types),
+            chain,
+            visited: visited.clone(),
+            sub_iter: None,
+        })
+    }
+
+    fn new_sub(&self, path: PathBuf, abs_path: PathBuf) -> Result<DirIterator> {
+        let chain = match &self.chain {
+            Some(chain) => match file_id(&path)? {
+                Some(id) if chain.contains(id) => {
+                    let err = io::Error::other("File system loop found");
+                    return Err(DirIteratorError::new(path, err));
+                }
+                Some(id) => Some(Arc::new(DirChain { id, parent: Some(chain.clone()) })),
+                None => None,
+            },
+            None => None,
+        };
+
+        let rd = read_dir(&path).into_result(&path)?;
+        let ignores = self.ignores.as_ref().map(|i| i.child(&abs_path));
+
+        Ok(DirIterator {
+            rd,
+            path,
+            abs_path,
+            options: self.options.clone(),
+            ignores,
+            overrides: self.overrides.clone(),
+            types: self.types.clone(),
+            chain,
+            visited: self.visited.clone(),
+            sub_iter: None,
+        })
+    }
+
+    fn direntry_is_skipped(&self, di: &DirEntry, is_dir: bool) -> bool {
+        let name = di.file_name();
+
+        if !self.options.hidden && name.to_string_lossy().starts_with('.') {
+            return true;
+        }
+
+        let abs_path = self.abs_path.join(&name);
+
+        match self.overrides.matched(&abs_path, is_dir) {
+            Match::Ignore(_) => return true,
+            Match::Whitelist(_) => return false,
+            Match::None => {}
+        };
+
+        if let Some(ignores) = &self.ignores {
+            if (is_dir && name == ".git") || ignores.is_ignored(&abs_path, is_dir) {
+                return true;
+            }
         }
+
+        self.types.matched(&abs_path, is_dir).is_ignore()
     }
 
-    fn next_from_sub(&mut self) -> Option<Result<String>> {
+    fn next_from_sub(&mut self) -> Option<Result<PathBuf>> {
         match &mut self.sub_iter {
             None => None,
             Some(s) => s.next()
@@ -53,8 +214,7 @@ impl DirIterator {
     }
 
     fn replace_sub_from_direntry(&mut self, entry: DirEntry) -> Option<Result<()>> {
-        let path = DirIterator::pathbuf_to_string(entry.path());
-        match DirIterator::new(&path) {
+        match self.new_sub(entry.path(), self.abs_path.join(entry.file_name())) {
             Ok(di) => {
                 self.sub_iter = Some(Box::new(di));
                 Some(Ok(()))
@@ -63,23 +223,48 @@ impl DirIterator {
         }
     }
 
-    fn direntry_is_directory(di: &DirEntry) -> Result<bool> {
-        let path = DirIterator::pathbuf_to_string(di.path());
-        match di.file_type().into_result(&path) {
-            Ok(file_type) => Ok(file_type.is_dir()),
-            Err(e) => Err(e),
+    /// Returns whether the entry is a directory, or `None` if it's something that shouldn't be visited at all:
+    /// a symlink that isn't being followed, or a special file like a socket or a FIFO.
+    fn direntry_is_directory(&self, di: &DirEntry) -> Result<Option<bool>> {
+        let path = di.path();
+        let mut file_type = di.file_type().into_result(&path)?;
+
+        if file_type.is_symlink() {
+            if !self.options.follow {
+                return Ok(None);
+            }
+            file_type = fs::metadata(&path).into_result(&path)?.file_type();
         }
-    }
 
-    fn next_from_direntry(&mut self, di: DirEntry) -> Option<Result<String>> {
-        let _s = di.path().to_str().unwrap().to_string();
+        match (file_type.is_dir(), file_type.is_file()) {
+            (true, _) => Ok(Some(true)),
+            (_, true) => Ok(Some(false)),
+            _ => Ok(None),
+        }
+    }
 
-        match DirIterator::direntry_is_directory(&di) {
+    fn next_from_direntry(&mut self, di: DirEntry) -> Option<Result<PathBuf>> {
+        let is_dir = match self.direntry_is_directory(&di) {
             Err(e) => return Some(Err(e)),
-            Ok(false) => return Some(Ok(DirIterator::pathbuf_to_string(di.path()))),
-            Ok(true) => {}
+            Ok(None) => return None,
+            Ok(Some(is_dir)) => is_dir,
         };
 
+        if self.direntry_is_skipped(&di, is_dir) {
+            return None;
+        }
+
+        if !is_dir {
+            if self.options.follow {
+                match file_id(&di.path()) {
+                    Ok(Some(id)) if !self.visited.insert(id) => return None,
+                    Ok(_) => {}
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+            return Some(Ok(di.path()));
+        }
+
         match self.replace_sub_from_direntry(di) {
             None => return None,
             Some(Err(e)) => return Some(Err(e)),
@@ -89,7 +274,7 @@ impl DirIterator {
         self.next_from_sub()
     }
 
-    fn next_from_rd(&mut self, path: &str) -> Option<Result<String>> {
+    fn next_from_rd(&mut self, path: &Path) -> Option<Result<PathBuf>> {
         loop {
             let value = self.rd.next()?;
 
@@ -106,7 +291,7 @@ impl DirIterator {
 }
 
 impl Iterator for DirIterator {
-    type Item = Result<String>;
+    type Item = Result<PathBuf>;
     fn next(&mut self) -> Option<Self::Item> {
         self.next_from_sub().or_else(|| {
             self.next_from_rd(&self.path.clone())
@@ -115,12 +300,12 @@ impl Iterator for DirIterator {
 }
 
 trait ToResult<T> {
-    fn into_result(self, path: &str) -> Result<T>;
+    fn into_result<P: AsRef<Path>>(self, path: P) -> Result<T>;
 }
 
 impl<T> ToResult<T> for io::Result<T> {
-    fn into_result(self, path: &str) -> Result<T> {
-        self.or_else(|e| Err(DirIteratorError::new(path, e)))
+    fn into_result<P: AsRef<Path>>(self, path: P) -> Result<T> {
+        self.map_err(|e| DirIteratorError::new(path, e))
     }
 }
 
@@ -130,20 +315,24 @@ mod tests {
     use crate::testdir::testdir::TestDir;
 
     fn test_dirs(temp: &mut TestDir, expected: Vec<&str>) {
-        let mut expected: Vec<String> = expected
+        test_dirs_with_options(temp, expected, &WalkOptions::default())
+    }
+
+    fn test_dirs_with_options(temp: &mut TestDir, expected: Vec<&str>, options: &WalkOptions) {
+        let mut expected: Vec<PathBuf> = expected
             .into_iter()
             .map(|x| {
                 let mut pb = temp.path().to_owned();
                 pb.push(x);
-                pb.to_str().unwrap().to_string()
+                pb
             })
             .collect();
         expected.sort();
 
-        let results: Vec<Result<String>> = DirIterator::new(temp.path_str()).unwrap().collect();
+        let results: Vec<Result<PathBuf>> = DirIterator::new(temp.path(), options, &VisitedFiles::default()).unwrap().collect();
         debug_assert!(results.iter().all(|x| x.is_ok()));
 
-        let mut paths: Vec<String> = results.into_iter().map(Result::unwrap).collect();
+        let mut paths: Vec<PathBuf> = results.into_iter().map(Result::unwrap).collect();
         paths.sort();
 
         debug_assert_eq!(paths.len(), expected.len());
@@ -210,4 +399,190 @@ mod tests {
 
         test_dirs(temp, expected);
     }
+
+    #[test]
+    pub fn test_skips_hidden_files() {
+        let mut temp = TestDir::new();
+        let temp = temp
+            .file("1", "abc")
+            .file(".2", "abc")
+            .subdir(".a", |a| {
+                a.file("3", "abc");
+            });
+
+        test_dirs(temp, vec!["1"]);
+
+        let options = WalkOptions { hidden: true, ..WalkOptions::default() };
+        test_dirs_with_options(temp, vec!["1", ".2", ".a/3"], &options);
+    }
+
+    #[test]
+    pub fn test_respects_nested_ignore_files() {
+        let mut temp = TestDir::new();
+        let temp = temp
+            .file(".gitignore", "*.log\ntarget/\n")
+            .file("1", "abc")
+            .file("2.log", "abc")
+            .subdir("target", |t| {
+                t.file("3", "abc");
+            })
+            .subdir("a", |a| {
+                a.file(".gitignore", "!keep.log\n")
+                    .file(".ignore", "4\n")
+                    .file("4", "abc")
+                    .file("5", "abc")
+                    .file("keep.log", "abc")
+                    .file("6.log", "abc");
+            });
+
+        test_dirs(temp, vec!["1", "a/5", "a/keep.log"]);
+
+        let options = WalkOptions { ignore: false, ..WalkOptions::default() };
+        test_dirs_with_options(temp, vec!["1", "2.log", "target/3", "a/4", "a/5", "a/keep.log", "a/6.log"], &options);
+    }
+
+    #[test]
+    pub fn test_skips_git_dir_and_info_exclude() {
+        let mut temp = TestDir::new();
+        let temp = temp
+            .file("1", "abc")
+            .file("2", "abc")
+            .subdir(".git", |git| {
+                git.file("packed-refs", "abc")
+                    .subdir("info", |info| {
+                        info.file("exclude", "2\n");
+                    });
+            });
+
+        test_dirs(temp, vec!["1"]);
+
+        let options = WalkOptions { hidden: true, ..WalkOptions::default() };
+        test_dirs_with_options(temp, vec!["1"], &options);
+    }
+
+    #[test]
+    pub fn test_include_and_exclude_globs() {
+        let mut temp = TestDir::new();
+        let temp = temp
+            .file("1.rs", "abc")
+            .file("2.txt", "abc")
+            .subdir("a", |a| {
+                a.file("3.rs", "abc")
+                    .file("4.txt", "abc");
+            })
+            .subdir("vendor", |v| {
+                v.file("5.rs", "abc")
+                    .subdir("b", |b| {
+                        b.file("6.rs", "abc");
+                    });
+            });
+
+        let options = WalkOptions { globs: vec!["*.rs".to_string()], ..WalkOptions::default() };
+        test_dirs_with_options(temp, vec!["1.rs", "a/3.rs", "vendor/5.rs", "vendor/b/6.rs"], &options);
+
+        let options = WalkOptions { globs: vec!["!vendor/".to_string()], ..WalkOptions::default() };
+        test_dirs_with_options(temp, vec!["1.rs", "2.txt", "a/3.rs", "a/4.txt"], &options);
+
+        let options = WalkOptions { globs: vec!["*.rs".to_string(), "!vendor/**".to_string()], ..WalkOptions::default() };
+        test_dirs_with_options(temp, vec!["1.rs", "a/3.rs"], &options);
+    }
+
+    #[test]
+    pub fn test_file_types() {
+        let mut temp = TestDir::new();
+        let temp = temp
+            .file("1.rs", "abc")
+            .file("2.yml", "abc")
+            .file("3.yaml", "abc")
+            .file("4.conf", "abc")
+            .subdir("a", |a| {
+                a.file("5.rs", "abc")
+                    .file("6.js", "abc");
+            });
+
+        let options = WalkOptions { types: vec![TypeSelection::Select("rust".to_string())], ..WalkOptions::default() };
+        test_dirs_with_options(temp, vec!["1.rs", "a/5.rs"], &options);
+
+        let options = WalkOptions { types: vec![TypeSelection::Negate("yaml".to_string())], ..WalkOptions::default() };
+        test_dirs_with_options(temp, vec!["1.rs", "4.conf", "a/5.rs", "a/6.js"], &options);
+
+        let options = WalkOptions {
+            type_defs: vec!["conf:*.conf".to_string()],
+            types: vec![TypeSelection::Select("conf".to_string()), TypeSelection::Select("yaml".to_string())],
+            ..WalkOptions::default()
+        };
+        test_dirs_with_options(temp, vec!["2.yml", "3.yaml", "4.conf"], &options);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    pub fn test_skips_symlinks_by_default() {
+        let mut temp = TestDir::new();
+        let temp = temp
+            .file("1", "abc")
+            .subdir("a", |a| {
+                a.file("2", "abc");
+            })
+            .symlink("3", "1")
+            .symlink("b", "a");
+
+        test_dirs(temp, vec!["1", "a/2"]);
+
+        // Each file is only yielded once, by whichever path to it comes first.
+        let options = WalkOptions { follow: true, ..WalkOptions::default() };
+        let mut names: Vec<String> = DirIterator::new(temp.path(), &options, &VisitedFiles::default())
+            .unwrap()
+            .map(|p| p.unwrap().strip_prefix(temp.path()).unwrap().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+
+        assert_eq!(names.len(), 2);
+        debug_assert!(names[0] == "1" || names[0] == "3");
+        debug_assert!(names[1] == "a/2" || names[1] == "b/2");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    pub fn test_follow_detects_loops() {
+        let mut temp = TestDir::new();
+        let temp = temp
+            .file("1", "abc")
+            .subdir("a", |a| {
+                a.file("2", "abc")
+                    .symlink("loop", "..");
+            });
+
+        let options = WalkOptions { follow: true, ..WalkOptions::default() };
+        let results: Vec<Result<PathBuf>> = DirIterator::new(temp.path(), &options, &VisitedFiles::default()).unwrap().collect();
+
+        let mut paths: Vec<PathBuf> = results.iter().filter_map(|r| r.as_ref().ok().cloned()).collect();
+        paths.sort();
+        let errors: Vec<&DirIteratorError> = results.iter().filter_map(|r| r.as_ref().err()).collect();
+
+        assert_eq!(paths.len(), 2);
+        assert_eq!(errors.len(), 1);
+        debug_assert!(errors[0].path.ends_with("a/loop"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    pub fn test_non_utf8_filenames() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let mut temp = TestDir::new();
+        temp.subdir("a", |_| {});
+
+        let name = OsStr::from_bytes(b"caf\xe9");
+        fs::write(temp.path().join(name), "abc").unwrap();
+        fs::write(temp.path().join("a").join(name), "abc").unwrap();
+
+        let mut paths: Vec<PathBuf> = DirIterator::new(temp.path(), &WalkOptions::default(), &VisitedFiles::default())
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        paths.sort();
+
+        assert_eq!(paths, vec![temp.path().join("a").join(name), temp.path().join(name)]);
+    }
 }
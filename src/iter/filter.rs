@@ -0,0 +1,131 @@
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::overrides::{Override, OverrideBuilder};
+use ignore::types::{Types, TypesBuilder};
+use ignore::Match;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Ignore files checked in each directory, from highest to lowest precedence.
+const IGNORE_FILES: [&str; 2] = [".ignore", ".gitignore"];
+
+fn build_matcher(dir: &Path, file: &Path) -> Option<Gitignore> {
+    if !file.is_file() {
+        return None;
+    }
+
+    let mut builder = GitignoreBuilder::new(dir);
+    builder.add(file);
+    builder.build().ok().filter(|gi| !gi.is_empty())
+}
+
+fn is_repo_root(dir: &Path) -> bool {
+    dir.join(".git").exists()
+}
+
+/// The ignore rules that apply to one directory, chained to the rules of the directories above it.
+///
+/// A rule in a deeper directory takes precedence over one in its parents, the same way git resolves
+/// nested `.gitignore` files.
+#[derive(Debug)]
+pub struct IgnoreStack {
+    parent: Option<Arc<IgnoreStack>>,
+    matchers: Vec<Gitignore>,
+}
+
+impl IgnoreStack {
+    fn push(parent: Option<Arc<IgnoreStack>>, dir: &Path) -> Arc<IgnoreStack> {
+        let mut matchers: Vec<Gitignore> = IGNORE_FILES
+            .iter()
+            .filter_map(|name| build_matcher(dir, &dir.join(name)))
+            .collect();
+
+        let git_dir = dir.join(".git");
+        if git_dir.is_dir() {
+            matchers.extend(build_matcher(dir, &git_dir.join("info").join("exclude")));
+        }
+
+        Arc::new(IgnoreStack { parent, matchers })
+    }
+
+    /// Builds the rules for `dir`, including those of its parents up to the root of its git repository.
+    /// `dir` should be an absolute path.
+    pub fn new(dir: &Path) -> Arc<IgnoreStack> {
+        let mut dirs: Vec<&Path> = Vec::new();
+
+        for ancestor in dir.ancestors() {
+            dirs.push(ancestor);
+            if is_repo_root(ancestor) {
+                break;
+            }
+        }
+
+        if !dirs.last().map(|d| is_repo_root(d)).unwrap_or(false) {
+            dirs.truncate(1);
+        }
+
+        dirs.into_iter()
+            .rev()
+            .fold(None, |parent, d| Some(IgnoreStack::push(parent, d)))
+            .expect("A path always has at least one ancestor")
+    }
+
+    pub fn child(self: &Arc<Self>, dir: &Path) -> Arc<IgnoreStack> {
+        IgnoreStack::push(Some(self.clone()), dir)
+    }
+
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        let mut level = Some(self);
+
+        while let Some(l) = level {
+            for matcher in &l.matchers {
+                match matcher.matched(path, is_dir) {
+                    Match::None => {}
+                    Match::Ignore(_) => return true,
+                    Match::Whitelist(_) => return false,
+                }
+            }
+            level = l.parent.as_deref();
+        }
+
+        false
+    }
+}
+
+/// Builds the `--include`/`--exclude` filter for a traversal rooted at `root`.
+/// The globs use gitignore syntax. Exclude globs start with `!`, and later globs take precedence over earlier ones.
+pub fn build_overrides<S: AsRef<str>>(root: &Path, globs: &[S]) -> Result<Override, ignore::Error> {
+    let mut builder = OverrideBuilder::new(root);
+
+    for glob in globs {
+        builder.add(glob.as_ref())?;
+    }
+
+    builder.build()
+}
+
+/// A `--type` or `--type-not` flag. Later selections take precedence over earlier ones.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TypeSelection {
+    Select(String),
+    Negate(String),
+}
+
+/// Builds the file type filter from the default types, the `name:glob` definitions given with `--type-add`,
+/// and the selected types.
+pub fn build_types<S: AsRef<str>>(defs: &[S], selections: &[TypeSelection]) -> Result<Types, ignore::Error> {
+    let mut builder = TypesBuilder::new();
+    builder.add_defaults();
+
+    for def in defs {
+        builder.add_def(def.as_ref())?;
+    }
+
+    for selection in selections {
+        match selection {
+            TypeSelection::Select(name) => builder.select(name),
+            TypeSelection::Negate(name) => builder.negate(name),
+        };
+    }
+
+    builder.build()
+}
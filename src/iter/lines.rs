@@ -0,0 +1,223 @@
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Write};
+
+/// How a line ends. The last line of a file has no terminator if the file doesn't end with a newline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineEnding {
+    None,
+    Lf,
+    CrLf,
+    Cr,
+}
+
+impl LineEnding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LineEnding::None => "",
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+            LineEnding::Cr => "\r",
+        }
+    }
+
+    /// The ending this line should be written with. `None` keeps it as it is,
+    /// and the missing terminator on the last line is never added.
+    pub fn normalized(self, eol: Option<LineEnding>) -> LineEnding {
+        match (self, eol) {
+            (LineEnding::None, _) | (_, None) => self,
+            (_, Some(e)) => e,
+        }
+    }
+}
+
+/// Splits bytes into lines and their endings. Unlike `str::lines`, a lone `\r` also ends a line.
+pub struct SplitLines<'a> {
+    rest: &'a [u8],
+}
+
+pub fn split_byte_lines(s: &[u8]) -> SplitLines<'_> {
+    SplitLines { rest: s }
+}
+
+/// `split_byte_lines` for a string. Line endings are ASCII, so every line is valid UTF-8 too.
+pub fn split_lines(s: &str) -> impl Iterator<Item = (&str, LineEnding)> {
+    let mut pos = 0;
+
+    split_byte_lines(s.as_bytes()).map(move |(line, ending)| {
+        let start = pos;
+        pos += line.len() + ending.as_str().len();
+        (&s[start..start + line.len()], ending)
+    })
+}
+
+impl<'a> Iterator for SplitLines<'a> {
+    type Item = (&'a [u8], LineEnding);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.rest.is_empty() {
+            return None;
+        }
+
+        let bytes = self.rest;
+        let (line, ending, len) = match bytes.iter().position(|&b| b == b'\n' || b == b'\r') {
+            None => (bytes, LineEnding::None, 0),
+            Some(i) if bytes[i] == b'\n' => (&bytes[..i], LineEnding::Lf, 1),
+            Some(i) if bytes.get(i + 1) == Some(&b'\n') => (&bytes[..i], LineEnding::CrLf, 2),
+            Some(i) => (&bytes[..i], LineEnding::Cr, 1),
+        };
+
+        self.rest = &bytes[line.len() + len..];
+        Some((line, ending))
+    }
+}
+
+/// The number of lines that end somewhere in `s`.
+pub fn count_line_endings(s: &[u8]) -> usize {
+    split_byte_lines(s).filter(|(_, e)| *e != LineEnding::None).count()
+}
+
+/// Reads lines along with their endings, so that writing each line followed by its ending gives back the original.
+pub struct Lines<R: BufRead> {
+    reader: R,
+    pending: VecDeque<(String, LineEnding)>,
+}
+
+impl<R: BufRead> Lines<R> {
+    pub fn new(reader: R) -> Self {
+        Lines {
+            reader,
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for Lines<R> {
+    type Item = io::Result<(String, LineEnding)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(line) = self.pending.pop_front() {
+            return Some(Ok(line));
+        }
+
+        // A chunk read up to a `\n` can't split a `\r\n`, but it may hold several lines ending in a lone `\r`.
+        let mut buf = Vec::new();
+        match self.reader.read_until(b'\n', &mut buf) {
+            Ok(0) => None,
+            Ok(_) => match String::from_utf8(buf) {
+                Ok(chunk) => {
+                    self.pending.extend(split_lines(&chunk).map(|(l, e)| (l.to_string(), e)));
+                    self.pending.pop_front().map(Ok)
+                }
+                Err(e) => Some(Err(io::Error::new(io::ErrorKind::InvalidData, e))),
+            },
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Rewrites every line ending written through it as `eol`.
+/// A `\r` at the end of one write may be the start of a `\r\n`, so it's held back until the next write or `finish`.
+pub struct EolWriter<W: Write> {
+    inner: W,
+    eol: LineEnding,
+    pending_cr: bool,
+}
+
+impl<W: Write> EolWriter<W> {
+    pub fn new(inner: W, eol: LineEnding) -> Self {
+        EolWriter {
+            inner,
+            eol,
+            pending_cr: false,
+        }
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        if self.pending_cr {
+            self.inner.write_all(self.eol.as_str().as_bytes())?;
+        }
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for EolWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let eol = self.eol.as_str().as_bytes();
+        let mut out = Vec::with_capacity(buf.len());
+
+        for &b in buf {
+            if self.pending_cr && b != b'\n' {
+                out.extend_from_slice(eol);
+            }
+
+            match b {
+                b'\r' => self.pending_cr = true,
+                b'\n' => {
+                    self.pending_cr = false;
+                    out.extend_from_slice(eol);
+                }
+                _ => {
+                    self.pending_cr = false;
+                    out.push(b);
+                }
+            }
+        }
+
+        self.inner.write_all(&out)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn _test_lines(input: &str, expected: Vec<(&str, LineEnding)>) {
+        let split: Vec<(&str, LineEnding)> = split_lines(input).collect();
+        debug_assert_eq!(split, expected);
+
+        let read: Vec<(String, LineEnding)> = Lines::new(input.as_bytes()).map(Result::unwrap).collect();
+        let read: Vec<(&str, LineEnding)> = read.iter().map(|(l, e)| (l.as_str(), *e)).collect();
+        debug_assert_eq!(read, expected);
+    }
+
+    #[test]
+    fn test_lines_keep_endings() {
+        use LineEnding::*;
+
+        _test_lines("", vec![]);
+        _test_lines("abc", vec![("abc", None)]);
+        _test_lines("abc\ndef\n", vec![("abc", Lf), ("def", Lf)]);
+        _test_lines("abc\r\ndef", vec![("abc", CrLf), ("def", None)]);
+        _test_lines("a\rb\r\nc\n\r", vec![("a", Cr), ("b", CrLf), ("c", Lf), ("", Cr)]);
+        _test_lines("\n\n", vec![("", Lf), ("", Lf)]);
+    }
+
+    #[test]
+    fn test_count_line_endings() {
+        debug_assert_eq!(count_line_endings(b"a\r\nb\rc\nd"), 3);
+        debug_assert_eq!(count_line_endings(b"abc"), 0);
+    }
+
+    #[test]
+    fn test_normalized_ending() {
+        debug_assert_eq!(LineEnding::CrLf.normalized(None), LineEnding::CrLf);
+        debug_assert_eq!(LineEnding::Cr.normalized(Some(LineEnding::Lf)), LineEnding::Lf);
+        debug_assert_eq!(LineEnding::None.normalized(Some(LineEnding::CrLf)), LineEnding::None);
+    }
+
+    #[test]
+    fn test_eol_writer() {
+        let mut w = EolWriter::new(Vec::new(), LineEnding::CrLf);
+        for chunk in ["a\nb\r", "\nc\r", "d\r\r"] {
+            w.write_all(chunk.as_bytes()).unwrap();
+        }
+        let out = w.finish().unwrap();
+
+        debug_assert_eq!(String::from_utf8(out).unwrap(), "a\r\nb\r\nc\r\nd\r\n\r\n");
+    }
+}
@@ -1,3 +1,4 @@
 pub mod concat;
-pub mod intersperse;
 pub mod dir_iter;
+pub mod filter;
+pub mod lines;
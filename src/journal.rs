@@ -0,0 +1,368 @@
+use crate::file::{replace_file, TempFile, NEW_SUFFIX};
+use std::env;
+use std::ffi::OsString;
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The suffix of the copies kept in the journal when `--backup` isn't given a suffix of its own.
+const ORIG_SUFFIX: &str = ".orig";
+
+/// Where runs are recorded: `$FAR_JOURNAL_DIR`, `$XDG_STATE_HOME/far` or `~/.local/state/far`, in that order.
+pub fn journal_dir() -> Option<PathBuf> {
+    let non_empty = |var| env::var_os(var).filter(|v| !v.is_empty()).map(PathBuf::from);
+
+    non_empty("FAR_JOURNAL_DIR")
+        .or_else(|| non_empty("XDG_STATE_HOME").map(|d| d.join("far")))
+        .or_else(|| non_empty("HOME").map(|d| d.join(".local").join("state").join("far")))
+}
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+/// FNV-1a, which is plenty to tell whether a file was changed again since a run, and needs no dependencies.
+fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for &b in bytes {
+        hash = (hash ^ b as u64).wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+fn hash_file(path: &Path) -> io::Result<u64> {
+    let mut file = fs::File::open(path)?;
+    let mut buf = vec![0; 64 * 1024];
+    let mut hash = FNV_OFFSET;
+
+    loop {
+        match file.read(&mut buf) {
+            Ok(0) => return Ok(hash),
+            Ok(n) => hash = fnv1a(hash, &buf[..n]),
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn path_to_bytes(path: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().to_vec()
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    PathBuf::from(std::ffi::OsStr::from_bytes(bytes))
+}
+
+#[cfg(not(unix))]
+fn path_to_bytes(path: &Path) -> Vec<u8> {
+    path.to_string_lossy().into_owned().into_bytes()
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// A file changed by a run. Both hashes are kept, so `undo` can tell whether the file or its backup was touched since.
+/// The fields are separated by NUL bytes, which can't be part of a path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Entry {
+    new_hash: u64,
+    backup_hash: u64,
+    target: PathBuf,
+    backup: PathBuf,
+}
+
+impl Entry {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = format!("{:016x}\0{:016x}\0", self.new_hash, self.backup_hash).into_bytes();
+        for path in [&self.target, &self.backup] {
+            out.extend(path_to_bytes(path));
+            out.push(0);
+        }
+        out
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Entry> {
+        let fields: Vec<&[u8]> = bytes.strip_suffix(b"\0")?.split(|&b| b == 0).collect();
+        let hash = |field: &[u8]| u64::from_str_radix(std::str::from_utf8(field).ok()?, 16).ok();
+
+        match fields.as_slice() {
+            [new_hash, backup_hash, target, backup] => Some(Entry {
+                new_hash: hash(new_hash)?,
+                backup_hash: hash(backup_hash)?,
+                target: path_from_bytes(target),
+                backup: path_from_bytes(backup),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Keeps a copy of every file a run changes and records it in the journal, so `--undo` can put it back.
+/// With a `suffix` the copy goes next to the file, and otherwise into the run's own directory in the journal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Backup {
+    pub suffix: Option<String>,
+    /// Named after the time the run started, so the last run sorts last.
+    pub run_dir: PathBuf,
+}
+
+impl Backup {
+    pub fn new(journal: &Path, suffix: Option<String>) -> Backup {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos());
+
+        Backup {
+            suffix,
+            run_dir: journal.join(format!("{:024}-{}", nanos, process::id())),
+        }
+    }
+
+    /// Claims an entry in the run directory named after a hash of `target`, so it only takes another try when two
+    /// targets hash the same. The directory is created the first time it's needed.
+    fn new_entry_file(&self, target: &Path) -> io::Result<(fs::File, PathBuf)> {
+        fs::create_dir_all(&self.run_dir)?;
+        let name = format!("{:016x}", fnv1a(FNV_OFFSET, &path_to_bytes(target)));
+
+        for index in 1.. {
+            let path = match index {
+                1 => self.run_dir.join(&name),
+                _ => self.run_dir.join(format!("{}-{}", name, index)),
+            };
+            match fs::OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => return Ok((file, path)),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        unreachable!("There's always a free entry")
+    }
+
+    /// Copies `target` before `new_contents` is swapped in for it, and records both.
+    pub fn save(&self, target: &Path, new_contents: &Path) -> io::Result<()> {
+        let target = fs::canonicalize(target)?;
+        let (mut entry_file, entry_path) = self.new_entry_file(&target)?;
+
+        let backup = match &self.suffix {
+            Some(suffix) => {
+                let mut name = OsString::from(&target);
+                name.push(suffix);
+                PathBuf::from(name)
+            }
+            None => {
+                let mut name = entry_path.into_os_string();
+                name.push(ORIG_SUFFIX);
+                PathBuf::from(name)
+            }
+        };
+        fs::copy(&target, &backup)?;
+
+        let entry = Entry {
+            new_hash: hash_file(new_contents)?,
+            backup_hash: hash_file(&backup)?,
+            target,
+            backup,
+        };
+        entry_file.write_all(&entry.to_bytes())?;
+        entry_file.sync_all()
+    }
+}
+
+#[derive(Debug)]
+pub enum UndoError {
+    NothingToUndo,
+    /// These files were changed again since the run, or their backups were, so nothing was restored.
+    Modified(Vec<PathBuf>),
+    IOError(io::Error),
+}
+
+impl From<io::Error> for UndoError {
+    fn from(e: io::Error) -> Self {
+        UndoError::IOError(e)
+    }
+}
+
+impl Display for UndoError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            UndoError::NothingToUndo => write!(f, "There's no run to undo."),
+            UndoError::Modified(paths) => {
+                write!(f, "Refusing to undo the last run, since these files have changed since:")?;
+                for path in paths {
+                    write!(f, "\n  {}", path.display())?;
+                }
+                Ok(())
+            }
+            UndoError::IOError(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+fn last_run(journal: &Path) -> Result<PathBuf, UndoError> {
+    let runs = match fs::read_dir(journal) {
+        Ok(runs) => runs,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Err(UndoError::NothingToUndo),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut last = None;
+    for run in runs {
+        let path = run?.path();
+        if path.is_dir() && last.as_ref().is_none_or(|l| path > *l) {
+            last = Some(path);
+        }
+    }
+
+    last.ok_or(UndoError::NothingToUndo)
+}
+
+fn read_entries(run: &Path) -> io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+
+    for file in fs::read_dir(run)? {
+        let path = file?.path();
+        if path.extension().is_some() {
+            continue;
+        }
+
+        // An empty entry means the run died before the swap, so there's nothing to restore.
+        if let Some(entry) = Entry::from_bytes(&fs::read(&path)?) {
+            entries.push(entry);
+        }
+    }
+
+    Ok(entries)
+}
+
+fn restore(entry: &Entry, in_journal: bool) -> io::Result<()> {
+    let mut tmp = TempFile::new(&entry.target, NEW_SUFFIX)?;
+    io::copy(&mut fs::File::open(&entry.backup)?, &mut tmp.file)?;
+    replace_file(&tmp.filename, &entry.target)?;
+
+    match in_journal {
+        true => Ok(()),
+        false => fs::remove_file(&entry.backup),
+    }
+}
+
+/// Restores every file changed by the last recorded run and forgets the run, so the one before it is next.
+/// Nothing is restored if any of the files or their backups changed since.
+/// Returns the restored files.
+pub fn undo_last_run(journal: &Path) -> Result<Vec<PathBuf>, UndoError> {
+    let run = last_run(journal)?;
+    let entries = read_entries(&run)?;
+
+    let mut to_restore = Vec::new();
+    let mut modified = Vec::new();
+    for entry in entries {
+        let current = hash_file(&entry.target).ok();
+        let backup_intact = hash_file(&entry.backup).ok() == Some(entry.backup_hash);
+
+        if current == Some(entry.backup_hash) {
+            // Already back the way it was.
+        } else if current == Some(entry.new_hash) && backup_intact {
+            to_restore.push(entry);
+        } else {
+            modified.push(entry.target);
+        }
+    }
+
+    if !modified.is_empty() {
+        return Err(UndoError::Modified(modified));
+    }
+
+    for entry in &to_restore {
+        restore(entry, entry.backup.starts_with(&run))?;
+    }
+    fs::remove_dir_all(&run)?;
+
+    Ok(to_restore.into_iter().map(|e| e.target).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::testdir::TestDir;
+
+    fn _change(backup: &Backup, path: &Path, contents: &str) {
+        let mut tmp = TempFile::new(path, NEW_SUFFIX).unwrap();
+        tmp.file.write_all(contents.as_bytes()).unwrap();
+        backup.save(path, &tmp.filename).unwrap();
+        replace_file(&tmp.filename, path).unwrap();
+    }
+
+    #[test]
+    fn test_entry_round_trip() {
+        let entry = Entry {
+            new_hash: 1,
+            backup_hash: u64::MAX,
+            target: PathBuf::from("/a b/c\nd"),
+            backup: PathBuf::from("/j/entry1.orig"),
+        };
+
+        debug_assert_eq!(Entry::from_bytes(&entry.to_bytes()), Some(entry));
+        debug_assert_eq!(Entry::from_bytes(b""), None);
+    }
+
+    #[test]
+    fn test_entries_are_named_after_their_target() {
+        let temp = TestDir::new();
+        let backup = Backup::new(&temp.path().join("journal"), None);
+
+        let (_, a) = backup.new_entry_file(Path::new("/a")).unwrap();
+        let (_, b) = backup.new_entry_file(Path::new("/b")).unwrap();
+        let (_, again) = backup.new_entry_file(Path::new("/a")).unwrap();
+
+        debug_assert_ne!(a, b);
+        debug_assert_eq!(again.file_name().unwrap().to_string_lossy(), format!("{}-2", a.file_name().unwrap().to_string_lossy()));
+    }
+
+    #[test]
+    fn test_undo_restores_the_last_run() {
+        let mut temp = TestDir::new();
+        temp.file("a", "one").file("b", "two").subdir("journal", |_| {});
+        let (path, journal) = (|name: &str| temp.path().join(name), temp.path().join("journal"));
+
+        let first = Backup::new(&journal, None);
+        _change(&first, &path("a"), "one!");
+        let second = Backup { suffix: Some("~".to_string()), ..Backup::new(&journal, None) };
+        _change(&second, &path("a"), "one!!");
+        _change(&second, &path("b"), "two!");
+        debug_assert_eq!(fs::read_to_string(path("b~")).unwrap(), "two");
+
+        let mut restored = undo_last_run(&journal).unwrap();
+        restored.sort();
+        debug_assert_eq!(restored.len(), 2);
+        debug_assert_eq!(fs::read_to_string(path("a")).unwrap(), "one!");
+        debug_assert_eq!(fs::read_to_string(path("b")).unwrap(), "two");
+        debug_assert!(!path("b~").exists());
+
+        undo_last_run(&journal).unwrap();
+        debug_assert_eq!(fs::read_to_string(path("a")).unwrap(), "one");
+        debug_assert!(matches!(undo_last_run(&journal), Err(UndoError::NothingToUndo)));
+    }
+
+    #[test]
+    fn test_undo_refuses_modified_files() {
+        let mut temp = TestDir::new();
+        temp.file("a", "one").file("b", "two");
+        let path = |name: &str| temp.path().join(name);
+        let journal = path("journal");
+
+        let backup = Backup::new(&journal, None);
+        _change(&backup, &path("a"), "one!");
+        _change(&backup, &path("b"), "two!");
+        fs::write(path("b"), "edited").unwrap();
+
+        match undo_last_run(&journal) {
+            Err(UndoError::Modified(paths)) => debug_assert_eq!(paths, vec![fs::canonicalize(path("b")).unwrap()]),
+            r => panic!("Expected the undo to be refused, got {:?}", r),
+        }
+        debug_assert_eq!(fs::read_to_string(path("a")).unwrap(), "one!");
+    }
+}
@@ -1,11 +1,21 @@
-use far::find_and_replace;
-use input::{ArgsError, FarMode, parse_cmdline};
+use far::{find_and_replace, print_summary, recover, undo};
+use input::{ArgsError, Command, FarMode, parse_cmdline};
+use summary::{EXIT_MATCHED, EXIT_PARTIAL_FAILURE, EXIT_USAGE};
+use std::process::exit;
 
+mod case;
+mod diff;
+mod encoding;
 mod far;
 mod file;
 mod input;
+mod interactive;
 mod iter;
+mod journal;
+mod pattern;
 mod replace;
+mod rules;
+mod summary;
 mod testdir;
 
 fn handle_argserror(e: ArgsError) {
@@ -14,9 +24,22 @@ fn handle_argserror(e: ArgsError) {
 
 fn main() {
     let args = match parse_cmdline() {
-        Ok(v) => v,
-        Err(e) => return handle_argserror(e)
+        Ok(Command::Replace(v)) => v,
+        Ok(Command::Recover(paths, options)) => match recover(paths, &options) {
+            0 => exit(EXIT_MATCHED),
+            _ => exit(EXIT_PARTIAL_FAILURE)
+        },
+        Ok(Command::Undo(journal)) => match undo(&journal) {
+            0 => exit(EXIT_MATCHED),
+            _ => exit(EXIT_PARTIAL_FAILURE)
+        },
+        Err(e) => {
+            handle_argserror(e);
+            exit(EXIT_USAGE)
+        }
     };
 
-    find_and_replace(args.paths, &args.pattern, &args.replacement, args.mode)
+    let summary = find_and_replace(args.paths, &args.rules, &args.options);
+    print_summary(&summary, &args.options);
+    exit(summary.exit_code())
 }
@@ -0,0 +1,540 @@
+use crate::case::case_variants;
+use aho_corasick::{AhoCorasick, Input, Match, MatchKind};
+use fancy_regex::Regex;
+use regex::bytes;
+use std::ops::Range;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaseMode {
+    Sensitive,
+    Insensitive,
+    /// Insensitive unless the pattern has an uppercase letter in it.
+    Smart,
+}
+
+/// How a pattern from the command line is read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PatternOptions {
+    pub binary: bool,
+    pub fixed_strings: bool,
+    pub case: CaseMode,
+    pub preserve_case: bool,
+    pub whole_words: bool,
+}
+
+impl Default for PatternOptions {
+    fn default() -> Self {
+        PatternOptions {
+            binary: false,
+            fixed_strings: false,
+            case: CaseMode::Sensitive,
+            preserve_case: false,
+            whole_words: false,
+        }
+    }
+}
+
+impl PatternOptions {
+    fn ignore_case(&self, pattern: &str) -> bool {
+        match self.case {
+            CaseMode::Sensitive => false,
+            CaseMode::Insensitive => true,
+            CaseMode::Smart => !has_uppercase_literal(pattern, self.fixed_strings),
+        }
+    }
+
+    /// The flag that can't go with `--preserve-case`, if it's set: the case variants are already matched exactly and
+    /// literally, so ignoring case or `--fixed-strings` would have no effect.
+    pub fn preserve_case_conflict(&self) -> Option<&'static str> {
+        if !self.preserve_case {
+            return None;
+        }
+
+        match self.case {
+            CaseMode::Insensitive => Some("--ignore-case"),
+            CaseMode::Smart => Some("--smart-case"),
+            CaseMode::Sensitive if self.fixed_strings => Some("--fixed-strings"),
+            CaseMode::Sensitive => None,
+        }
+    }
+}
+
+fn skip_escape(rest: &str) -> &str {
+    let mut chars = rest.chars();
+
+    match chars.next() {
+        Some('p') | Some('P') if chars.as_str().starts_with('{') => chars.as_str().split_once('}').map_or("", |(_, r)| r),
+        Some('p') | Some('P') => {
+            chars.next();
+            chars.as_str()
+        }
+        _ => chars.as_str(),
+    }
+}
+
+/// Smart case only looks at what the pattern matches literally, so escapes like `\W` or `\p{Lu}` and
+/// group names don't count as uppercase letters.
+fn has_uppercase_literal(pattern: &str, fixed_strings: bool) -> bool {
+    if fixed_strings {
+        return pattern.chars().any(char::is_uppercase);
+    }
+
+    let mut rest = pattern;
+    while let Some(c) = rest.chars().next() {
+        rest = &rest[c.len_utf8()..];
+
+        match c {
+            '\\' => rest = skip_escape(rest),
+            '(' if rest.starts_with("?P<") || (rest.starts_with("?<") && !rest.starts_with("?<=") && !rest.starts_with("?<!")) => {
+                rest = rest.split_once('>').map_or("", |(_, r)| r)
+            }
+            c if c.is_uppercase() => return true,
+            _ => {}
+        }
+    }
+
+    false
+}
+
+#[derive(Debug)]
+pub enum PatternError {
+    Text(fancy_regex::Error),
+    Bytes(regex::Error),
+}
+
+/// A compiled search pattern.
+///
+/// Text patterns only match UTF-8 files (or files decoded to UTF-8).
+/// Byte patterns (`--binary`) are compiled with `regex::bytes` with Unicode turned off, so `.` and `\xff` match
+/// any single byte and files don't need to be UTF-8 or look like text.
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    Text(TextPattern),
+    Bytes(bytes::Regex),
+}
+
+/// A pattern that matches text.
+///
+/// Regexes support the full fancy_regex syntax, including lookaround and backreferences, and `$1`/`$name` in
+/// the replacement refer to their groups. Literals (`--fixed-strings`) are found with Aho-Corasick, and the
+/// replacement is used as it is.
+///
+#[derive(Debug, Clone)]
+pub enum TextPattern {
+    Regex(Regex),
+    Literal(Literals),
+}
+
+/// One or more literals found with Aho-Corasick.
+///
+/// With `replacements` (`--preserve-case`), each literal comes with its own replacement,
+/// which is used instead of the one passed in.
+#[derive(Debug, Clone)]
+pub struct Literals {
+    ac: AhoCorasick,
+    replacements: Option<Vec<String>>,
+    whole_words: bool,
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Whether the text on either side of `range` isn't part of a word, the way `(?<!\w)` and `(?!\w)` check it.
+fn is_whole_word(input: &str, range: &Range<usize>) -> bool {
+    !input[..range.start].chars().next_back().is_some_and(is_word_char)
+        && !input[range.end..].chars().next().is_some_and(is_word_char)
+}
+
+fn next_char_boundary(input: &str, i: usize) -> usize {
+    i + input[i..].chars().next().map_or(1, char::len_utf8)
+}
+
+impl Literals {
+    /// The first match that starts at or after `pos`.
+    fn find_at(&self, input: &str, mut pos: usize) -> Option<Match> {
+        while pos <= input.len() {
+            let m = self.ac.find(Input::new(input).span(pos..input.len()))?;
+
+            // A match that isn't a whole word may still overlap one, so the search picks up right after its start.
+            if self.whole_words && !is_whole_word(input, &m.range()) {
+                pos = next_char_boundary(input, m.start());
+                continue;
+            }
+
+            return Some(m);
+        }
+
+        None
+    }
+
+    fn find_iter<'a>(&'a self, input: &'a str) -> impl Iterator<Item = Match> + 'a {
+        let mut pos = 0;
+
+        std::iter::from_fn(move || {
+            let m = self.find_at(input, pos)?;
+            pos = match m.is_empty() {
+                true => next_char_boundary(input, m.end()),
+                false => m.end(),
+            };
+            Some(m)
+        })
+    }
+
+    fn replacement<'a>(&'a self, m: &Match, replacement: &'a str) -> &'a str {
+        match &self.replacements {
+            Some(replacements) => &replacements[m.pattern().as_usize()],
+            None => replacement,
+        }
+    }
+}
+
+impl Pattern {
+    pub fn new(pattern: &str, options: &PatternOptions) -> Result<Pattern, PatternError> {
+        let ignore_case = options.ignore_case(pattern);
+        let escaped = match options.fixed_strings {
+            true => regex::escape(pattern),
+            false => pattern.to_string(),
+        };
+
+        // The pattern is wrapped so it can't start or end next to a word character. Unlike `\b`, this still
+        // works when the pattern itself starts or ends with something that isn't part of a word, like `-x` or `x()`.
+        if options.binary {
+            let escaped = match options.whole_words {
+                true => format!("\\b{{start-half}}(?:{})\\b{{end-half}}", escaped),
+                false => escaped,
+            };
+            return Pattern::bytes(&escaped, ignore_case).map_err(PatternError::Bytes);
+        }
+
+        // Aho-Corasick only knows how to ignore ASCII case, so anything else is left to the regex engine.
+        if options.fixed_strings && (!ignore_case || pattern.is_ascii()) {
+            return Ok(Pattern::Text(TextPattern::literal(pattern, ignore_case, options.whole_words)));
+        }
+
+        let flags = if ignore_case { "(?i)" } else { "" };
+        let escaped = match options.whole_words {
+            true => format!("(?<!\\w)(?:{})(?!\\w)", escaped),
+            false => escaped,
+        };
+        Pattern::text(&format!("{}{}", flags, escaped)).map_err(PatternError::Text)
+    }
+
+    /// Matches every casing of `pattern` (see `case_variants`) and replaces each with the same casing of `replacement`.
+    /// Both are taken literally.
+    pub fn preserving_case(pattern: &str, replacement: &str, whole_words: bool) -> Pattern {
+        let (patterns, replacements): (Vec<String>, Vec<String>) = case_variants(pattern, replacement).into_iter().unzip();
+
+        let ac = AhoCorasick::builder()
+            .match_kind(MatchKind::LeftmostLongest)
+            .build(&patterns)
+            .expect("A handful of literals is always a valid automaton");
+
+        Pattern::Text(TextPattern::Literal(Literals {
+            ac,
+            replacements: Some(replacements),
+            whole_words,
+        }))
+    }
+
+    /// A fixed-string replacement is used as it is. If the pattern had to be compiled to a regex anyway,
+    /// `$` is escaped so it isn't read as a group.
+    pub fn replacement(&self, replacement: &str, options: &PatternOptions) -> String {
+        match (self, options.fixed_strings) {
+            (Pattern::Text(TextPattern::Literal(_)), _) | (_, false) => replacement.to_string(),
+            (Pattern::Text(_), true) => replacement.replace('$', "$$"),
+            (Pattern::Bytes(_), true) => replacement.replace('\\', "\\\\").replace('$', "$$"),
+        }
+    }
+
+    pub fn text(pattern: &str) -> Result<Pattern, fancy_regex::Error> {
+        TextPattern::regex(pattern).map(Pattern::Text)
+    }
+
+    pub fn bytes(pattern: &str, ignore_case: bool) -> Result<Pattern, regex::Error> {
+        bytes::RegexBuilder::new(pattern)
+            .unicode(false)
+            .case_insensitive(ignore_case)
+            .build()
+            .map(Pattern::Bytes)
+    }
+
+    #[cfg(test)]
+    pub fn is_match(&self, s: &str) -> bool {
+        match self {
+            Pattern::Text(pattern) => matches!(pattern.find_iter(s).next(), Some(Ok(_))),
+            Pattern::Bytes(re) => re.is_match(s.as_bytes()),
+        }
+    }
+}
+
+impl TextPattern {
+    pub fn regex(pattern: &str) -> Result<TextPattern, fancy_regex::Error> {
+        Regex::new(pattern).map(TextPattern::Regex)
+    }
+
+    pub fn literal(pattern: &str, ascii_case_insensitive: bool, whole_words: bool) -> TextPattern {
+        let ac = AhoCorasick::builder()
+            .ascii_case_insensitive(ascii_case_insensitive)
+            .build([pattern])
+            .expect("A single literal is always a valid automaton");
+
+        TextPattern::Literal(Literals {
+            ac,
+            replacements: None,
+            whole_words,
+        })
+    }
+
+    /// The byte ranges of the matches in `input`, from left to right.
+    /// A regex can fail partway through, e.g. when it hits the backtracking limit, and the error is the last item.
+    pub fn find_iter<'a>(&'a self, input: &'a str) -> Box<dyn Iterator<Item = Result<Range<usize>, fancy_regex::Error>> + 'a> {
+        match self {
+            TextPattern::Regex(re) => {
+                // fancy_regex keeps returning the same error once it's failed, so the search ends with the first one.
+                let mut failed = false;
+                Box::new(re.find_iter(input).map_while(move |m| match failed {
+                    true => None,
+                    false => {
+                        failed = m.is_err();
+                        Some(m.map(|m| m.start()..m.end()))
+                    }
+                }))
+            }
+            TextPattern::Literal(literals) => Box::new(literals.find_iter(input).map(|m| Ok(m.range()))),
+        }
+    }
+
+    /// Calls `f` with the byte range of every match in `input` and the text to replace it with, from left to right.
+    /// Stops at the first error, whether it's from `f` or from the regex.
+    pub fn for_each_replacement<F, E>(&self, input: &str, replacement: &str, mut f: F) -> Result<(), E>
+    where
+        F: FnMut(Range<usize>, &str) -> Result<(), E>,
+        E: From<fancy_regex::Error>,
+    {
+        match self {
+            TextPattern::Regex(re) => {
+                let mut expanded = String::new();
+
+                for caps in re.captures_iter(input) {
+                    let caps = caps?;
+                    let m = caps.get(0).expect("A match always has a 0th group");
+
+                    expanded.clear();
+                    caps.expand(replacement, &mut expanded);
+                    f(m.start()..m.end(), &expanded)?;
+                }
+
+                Ok(())
+            }
+            TextPattern::Literal(literals) => literals
+                .find_iter(input)
+                .try_for_each(|m| f(m.range(), literals.replacement(&m, replacement))),
+        }
+    }
+
+    /// The first match that starts at or after `pos`, and the text to replace it with.
+    /// Unlike searching `&input[pos..]`, lookbehind and word boundaries still see the text before `pos`.
+    pub fn replacement_at(
+        &self,
+        input: &str,
+        pos: usize,
+        replacement: &str,
+    ) -> Result<Option<(Range<usize>, String)>, fancy_regex::Error> {
+        match self {
+            TextPattern::Regex(re) => {
+                let caps = match re.captures_from_pos(input, pos)? {
+                    Some(caps) => caps,
+                    None => return Ok(None),
+                };
+                let m = caps.get(0).expect("A match always has a 0th group");
+
+                let mut expanded = String::new();
+                caps.expand(replacement, &mut expanded);
+                Ok(Some((m.start()..m.end(), expanded)))
+            }
+            TextPattern::Literal(literals) => Ok(literals
+                .find_at(input, pos)
+                .map(|m| (m.range(), literals.replacement(&m, replacement).to_string()))),
+        }
+    }
+}
+
+/// Turns a `--binary` replacement into the bytes to write: `\xNN` becomes the byte `NN` and `\\` a single
+/// backslash, so replacements can hold bytes that aren't utf-8, just like the pattern can. Anything else is kept as is.
+pub fn unescape_bytes(replacement: &str) -> Vec<u8> {
+    let input = replacement.as_bytes();
+    let mut out = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        if input[i] == b'\\' {
+            if input.get(i + 1) == Some(&b'\\') {
+                out.push(b'\\');
+                i += 2;
+                continue;
+            }
+            if input.get(i + 1) == Some(&b'x') {
+                let byte = replacement
+                    .get(i + 2..i + 4)
+                    .filter(|hex| hex.bytes().all(|b| b.is_ascii_hexdigit()))
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                if let Some(byte) = byte {
+                    out.push(byte);
+                    i += 4;
+                    continue;
+                }
+            }
+        }
+        out.push(input[i]);
+        i += 1;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bytes_pattern_matches_raw_bytes() {
+        let pattern = match Pattern::bytes("caf\\xe9", false).unwrap() {
+            Pattern::Bytes(re) => re,
+            _ => unreachable!(),
+        };
+
+        debug_assert!(pattern.is_match(b"un caf\xe9"));
+        debug_assert!(!pattern.is_match("caf\u{e9}".as_bytes()));
+    }
+
+    #[test]
+    fn test_unescape_bytes() {
+        debug_assert_eq!(unescape_bytes("caf\\xe8"), b"caf\xe8");
+        debug_assert_eq!(unescape_bytes("\\x4A$1\\xff"), b"J$1\xff");
+        debug_assert_eq!(unescape_bytes("\\\\xe8"), b"\\xe8");
+        debug_assert_eq!(unescape_bytes("\\xz1 \\x+1 \\x4 \\n"), b"\\xz1 \\x+1 \\x4 \\n");
+
+        let options = PatternOptions { binary: true, fixed_strings: true, ..Default::default() };
+        let pattern = Pattern::new("a", &options).unwrap();
+        debug_assert_eq!(unescape_bytes(&pattern.replacement("\\xe8$", &options)), b"\\xe8$$");
+    }
+
+    #[test]
+    fn test_literal_pattern() {
+        let pattern = TextPattern::literal("foo.bar()", false, false);
+        let input = "foo.bar() fooxbar() foo.bar()";
+
+        debug_assert_eq!(pattern.find_iter(input).collect::<Result<Vec<_>, _>>().unwrap(), vec![0..9, 20..29]);
+
+        let mut replacements = Vec::new();
+        pattern
+            .for_each_replacement(input, "$1", |range, s| {
+                replacements.push((range, s.to_string()));
+                Ok::<_, fancy_regex::Error>(())
+            })
+            .unwrap();
+        debug_assert_eq!(replacements, vec![(0..9, "$1".to_string()), (20..29, "$1".to_string())]);
+    }
+
+    fn _test_case(pattern: &str, options: PatternOptions, matches: &[&str], non_matches: &[&str]) {
+        let compiled = Pattern::new(pattern, &options).unwrap();
+
+        for s in matches {
+            debug_assert!(compiled.is_match(s), "{} should match {}", pattern, s);
+        }
+        for s in non_matches {
+            debug_assert!(!compiled.is_match(s), "{} shouldn't match {}", pattern, s);
+        }
+    }
+
+    #[test]
+    fn test_ignore_case() {
+        for (binary, fixed_strings) in [(false, false), (false, true), (true, false), (true, true)] {
+            let options = |case| PatternOptions { binary, fixed_strings, case, ..PatternOptions::default() };
+
+            _test_case("user", options(CaseMode::Insensitive), &["USER", "User"], &["usr"]);
+            _test_case("user", options(CaseMode::Smart), &["USER", "User"], &[]);
+            _test_case("User", options(CaseMode::Smart), &["User"], &["user", "USER"]);
+            _test_case("User", options(CaseMode::Sensitive), &["User"], &["user"]);
+        }
+
+        let insensitive = PatternOptions { fixed_strings: true, case: CaseMode::Insensitive, ..PatternOptions::default() };
+        _test_case("caf\u{e9}.", insensitive, &["CAF\u{c9}."], &["CAF\u{c9}x"]);
+    }
+
+    #[test]
+    fn test_smart_case_ignores_escapes() {
+        debug_assert!(!has_uppercase_literal("\\W+\\p{Lu}\\PL(?P<Name>x)(?<Id>y)", false));
+        debug_assert!(has_uppercase_literal("(?<=a)B", false));
+        debug_assert!(has_uppercase_literal("\\W", true));
+    }
+
+    #[test]
+    fn test_preserving_case() {
+        let pattern = match Pattern::preserving_case("user_id", "account_id", false) {
+            Pattern::Text(pattern) => pattern,
+            _ => unreachable!(),
+        };
+        let input = "user_id UserId USER_ID userId user-id user_idx";
+
+        let mut out = String::new();
+        let mut last = 0;
+        pattern
+            .for_each_replacement(input, "unused", |range, s| {
+                out += &input[last..range.start];
+                out += s;
+                last = range.end;
+                Ok::<_, fancy_regex::Error>(())
+            })
+            .unwrap();
+        out += &input[last..];
+
+        debug_assert_eq!(out, "account_id AccountId ACCOUNT_ID accountId account-id account_idx");
+    }
+
+    #[test]
+    fn test_whole_words() {
+        for (binary, fixed_strings) in [(false, false), (false, true), (true, false), (true, true)] {
+            let options = PatternOptions { binary, fixed_strings, whole_words: true, ..PatternOptions::default() };
+
+            _test_case("id", options, &["id", "user.id = 1", "(id)", "width id"], &["width", "valid", "id_x", "user_id"]);
+            _test_case("-x", options, &["a -x", "-x", "a-x -x"], &["a-xb"]);
+            _test_case("x-", options, &["x- y", "(x-)", "x-"], &["ax-", "x-y"]);
+        }
+    }
+
+    #[test]
+    fn test_whole_words_finds_overlapping_matches() {
+        let pattern = TextPattern::literal("aa", false, true);
+
+        debug_assert_eq!(pattern.find_iter("aaa aa").collect::<Result<Vec<_>, _>>().unwrap(), vec![4..6]);
+        debug_assert_eq!(pattern.find_iter("\u{e9}aa aa").collect::<Result<Vec<_>, _>>().unwrap(), vec![5..7]);
+    }
+
+    #[test]
+    fn test_regex_errors_are_reported() {
+        let pattern = TextPattern::regex(r"(?=x)x|(a|a)*\1b|x").unwrap();
+        let input = format!("x {}c x", "a".repeat(40));
+
+        let found: Vec<_> = pattern.find_iter(&input).collect();
+        debug_assert!(matches!(found.as_slice(), [Ok(_), Err(_)]));
+
+        let mut replaced = 0;
+        let result = pattern.for_each_replacement(&input, "Y", |_, _| {
+            replaced += 1;
+            Ok::<_, fancy_regex::Error>(())
+        });
+        debug_assert!(result.is_err());
+        debug_assert_eq!(replaced, 1);
+    }
+
+    #[test]
+    fn test_replacement_at() {
+        let regex = TextPattern::regex(r"(?<=x)(\w)").unwrap();
+        let literal = TextPattern::literal("ab", false, true);
+
+        debug_assert_eq!(regex.replacement_at("xa xb", 1, "<$1>").unwrap(), Some((1..2, "<a>".to_string())));
+        debug_assert_eq!(regex.replacement_at("xa xb", 2, "<$1>").unwrap(), Some((4..5, "<b>".to_string())));
+        debug_assert_eq!(literal.replacement_at("ab cab ab", 1, "x").unwrap(), Some((7..9, "x".to_string())));
+        debug_assert_eq!(literal.replacement_at("ab", 1, "x").unwrap(), None);
+    }
+}
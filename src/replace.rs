@@ -1,61 +1,208 @@
+use crate::diff::unified_diff;
+use crate::encoding::FileEncoding;
 use crate::file::*;
-use crate::iter::intersperse::Intersperse;
-use ascii_utils::Check;
-use fancy_regex::Regex;
+use crate::input::{FarMode, FarOptions};
+use crate::interactive::Decision;
+use crate::pattern::{unescape_bytes, Pattern, TextPattern};
+use crate::rules::Rule;
+use crate::iter::lines::{count_line_endings, split_byte_lines, split_lines, EolWriter, LineEnding, Lines};
+use memmap2::Mmap;
+use regex::bytes;
+use std::borrow::Cow;
 use std::fmt::Display;
 use std::fs;
-use std::io::{self, BufRead, BufReader, Write};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::str;
 
-fn replace_string(input: &str, pattern: &Regex, replacement: &str) -> String {
-    pattern.replace_all(input, replacement).to_string()
+fn replace_string(input: &str, pattern: &TextPattern, replacement: &str) -> Result<String, ReplaceError> {
+    let mut out = Vec::with_capacity(input.len());
+    write_replaced(input, pattern, replacement, &mut out)?;
+    Ok(String::from_utf8(out).expect("Replacing in a string always gives a string"))
 }
 
-fn write_to_file<I: Iterator<Item = io::Result<String>>>(
-    file: &mut fs::File,
-    strings: I,
-) -> io::Result<()> {
-    for string in strings {
-        file.write_all(string?.as_bytes())?;
+fn match_offsets(input: &str, pattern: &TextPattern) -> Result<Vec<usize>, ReplaceError> {
+    pattern.find_iter(input).map(|m| Ok(m?.start)).collect()
+}
+
+/// Writes `input` with every match replaced to `out` a piece at a time, so the new contents are never all in memory.
+/// Returns where each match started.
+fn write_replaced<W: Write + ?Sized>(
+    input: &str,
+    pattern: &TextPattern,
+    replacement: &str,
+    out: &mut W,
+) -> Result<Vec<usize>, ReplaceError> {
+    let mut offsets = Vec::new();
+    let mut last = 0;
+
+    pattern.for_each_replacement(input, replacement, |m, new| {
+        out.write_all(&input.as_bytes()[last..m.start])?;
+        out.write_all(new.as_bytes())?;
+        offsets.push(m.start);
+        last = m.end;
+        Ok::<_, ReplaceError>(())
+    })?;
+
+    out.write_all(&input.as_bytes()[last..])?;
+    Ok(offsets)
+}
+
+/// `write_replaced` for `--binary`, where the input and replacement are raw bytes.
+fn write_bytes_replaced<W: Write + ?Sized>(
+    input: &[u8],
+    pattern: &bytes::Regex,
+    replacement: &[u8],
+    out: &mut W,
+) -> io::Result<Vec<usize>> {
+    let mut offsets = Vec::new();
+    let mut last = 0;
+    let mut expanded = Vec::new();
+
+    for caps in pattern.captures_iter(input) {
+        let m = caps.get(0).expect("A match always has a 0th group");
+        out.write_all(&input[last..m.start()])?;
+
+        expanded.clear();
+        caps.expand(replacement, &mut expanded);
+        out.write_all(&expanded)?;
+
+        offsets.push(m.start());
+        last = m.end();
     }
-    Ok(())
+
+    out.write_all(&input[last..])?;
+    Ok(offsets)
 }
 
-fn string_is_printable(s: &str) -> bool {
-    s.chars().all(|c| c.is_printable() || c.is_space())
+/// Calls `write` with `out`, rewriting the line endings it writes if `eol` is given.
+fn with_eol<F, T, E>(eol: Option<LineEnding>, out: &mut dyn Write, write: F) -> Result<T, E>
+where
+    F: FnOnce(&mut dyn Write) -> Result<T, E>,
+    E: From<io::Error>,
+{
+    match eol {
+        None => write(out),
+        Some(eol) => {
+            let mut writer = EolWriter::new(out, eol);
+            let written = write(&mut writer)?;
+            writer.finish()?;
+            Ok(written)
+        }
+    }
 }
 
-fn file_is_printable(path: &str) -> io::Result<bool> {
-    let cap = 256 * 1024;
-    let file = fs::File::open(path)?;
-    let mut br = BufReader::with_capacity(cap, file);
+/// Passes everything written to it on to `inner`, remembering whether it differs from `original`.
+struct ChangeDetector<'a, W: Write> {
+    original: &'a [u8],
+    pos: usize,
+    changed: bool,
+    inner: W,
+}
 
-    loop {
-        let length = {
-            let buf = br.fill_buf()?;
+impl<'a, W: Write> ChangeDetector<'a, W> {
+    fn new(original: &'a [u8], inner: W) -> Self {
+        ChangeDetector {
+            original,
+            pos: 0,
+            changed: false,
+            inner,
+        }
+    }
 
-            if let Ok(s) = str::from_utf8(buf) {
-                if !string_is_printable(s) {
-                    return Ok(false);
-                }
-            } else {
-                return Ok(false);
-            }
-            buf.len()
-        };
+    fn changed(&self) -> bool {
+        self.changed || self.pos != self.original.len()
+    }
+}
 
-        if length == 0 {
-            return Ok(true);
+impl<W: Write> Write for ChangeDetector<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        let end = self.pos + n;
+        self.changed |= self.original.get(self.pos..end) != Some(&buf[..n]);
+        self.pos = end;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// The matches found in a single file, by the (1-based) line each one starts on.
+/// `changed` is set when the replacement alters the file (or would, in a dry run), and
+/// `diff` is only filled in when a diff was requested and the file changes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileReport {
+    pub match_lines: Vec<usize>,
+    pub changed: bool,
+    pub diff: Option<String>,
+    /// With `--atomic-run`, the new contents waiting to be swapped in by `commit_swaps`.
+    pub pending: Option<PendingSwap>,
+}
+
+/// A finished temp file that hasn't been swapped in for its target yet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingSwap {
+    pub tmp: PathBuf,
+    pub target: PathBuf,
+}
+
+impl FileReport {
+    pub fn matches(&self) -> usize {
+        self.match_lines.len()
+    }
+
+    fn add_offsets(&mut self, contents: &[u8], offsets: Vec<usize>) {
+        let mut line = 1;
+        let mut prev = 0;
+
+        for offset in offsets {
+            line += count_line_endings(&contents[prev..offset]);
+            prev = offset;
+            self.match_lines.push(line);
         }
+    }
+
+    fn add_diff(&mut self, filename: &Path, original: &str, new: &str) {
+        if original != new {
+            self.diff = Some(unified_diff(&filename.to_string_lossy(), original, new));
+        }
+    }
+}
 
-        br.consume(length);
+fn write_to_file<I: Iterator<Item = Result<String, ReplaceError>>>(
+    file: &mut fs::File,
+    strings: I,
+) -> Result<(), ReplaceError> {
+    for string in strings {
+        file.write_all(string?.as_bytes())?;
     }
+    Ok(())
+}
+
+/// Like git, a file is treated as binary if there's a NUL byte in its first `scan` bytes.
+/// Anything else, including control characters and escape codes, is fair game.
+fn looks_binary(contents: &[u8], scan: usize) -> bool {
+    contents[..contents.len().min(scan)].contains(&0)
+}
+
+fn file_looks_binary(path: &Path, scan: usize) -> io::Result<bool> {
+    let mut head = Vec::new();
+    fs::File::open(path)?.take(scan as u64).read_to_end(&mut head)?;
+    Ok(looks_binary(&head, scan))
 }
 
 #[derive(Debug)]
 pub enum ReplaceError {
-    FileTooBig,
-    FileNotPrintable,
+    FileTooBig { size: u64, limit: u64 },
+    FileIsBinary,
+    FileNotUtf8,
+    FileNotDecodable(&'static str),
+    NotEncodable(&'static str),
+    /// The regex gave up partway through the file, e.g. on hitting the backtracking limit.
+    RegexFailed(fancy_regex::Error),
     IOError(io::Error),
 }
 
@@ -65,14 +212,30 @@ impl From<io::Error> for ReplaceError {
     }
 }
 
+impl From<fancy_regex::Error> for ReplaceError {
+    fn from(e: fancy_regex::Error) -> Self {
+        ReplaceError::RegexFailed(e)
+    }
+}
+
 impl Display for ReplaceError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
         write!(
             f,
             "{}",
             match self {
-                ReplaceError::FileTooBig => "The file is too big.".to_string(),
-                ReplaceError::FileNotPrintable => "The file is not printable.".to_string(),
+                ReplaceError::FileTooBig { size, limit } =>
+                    format!("Skipped: the file is {} bytes, over the {} byte limit.", size, limit),
+                ReplaceError::FileIsBinary =>
+                    "Skipped: the file looks binary. Use --binary to replace in it anyway.".to_string(),
+                ReplaceError::FileNotUtf8 =>
+                    "Skipped: the file isn't valid UTF-8. Use --binary to replace in it anyway.".to_string(),
+                ReplaceError::FileNotDecodable(encoding) =>
+                    format!("Skipped: the file isn't valid {}.", encoding),
+                ReplaceError::NotEncodable(encoding) =>
+                    format!("The replaced text can't be written back as {}.", encoding),
+                ReplaceError::RegexFailed(e) =>
+                    format!("The regex failed partway through the file, so it was left alone: {}", e),
                 ReplaceError::IOError(e) => e.to_string(),
             }
         )
@@ -80,66 +243,624 @@ impl Display for ReplaceError {
 }
 
 fn conv_result<T>(res: io::Result<T>) -> Result<T, ReplaceError> {
-    res.or_else(|e| Err(ReplaceError::from(e)))
+    res.map_err(ReplaceError::from)
 }
 
-fn get_contents_of_file(filename: &str) -> Result<String, ReplaceError> {
-    if conv_result(fs::metadata(filename))?.len() > 4 * 1024 * 1024 {
-        return Err(ReplaceError::FileTooBig);
+/// Reading lines fails with `InvalidData` when the file isn't UTF-8.
+fn conv_read_result<T>(res: io::Result<T>) -> Result<T, ReplaceError> {
+    res.map_err(|e| match e.kind() {
+        io::ErrorKind::InvalidData => ReplaceError::FileNotUtf8,
+        _ => ReplaceError::IOError(e),
+    })
+}
+
+fn check_file_size(filename: &Path, max_filesize: Option<u64>) -> Result<(), ReplaceError> {
+    let size = conv_result(fs::metadata(filename))?.len();
+
+    match max_filesize {
+        Some(limit) if size > limit => Err(ReplaceError::FileTooBig { size, limit }),
+        _ => Ok(()),
     }
+}
+
+/// Files at least this big are mapped into memory instead of read, so that files bigger than memory can be searched.
+const MMAP_THRESHOLD: u64 = 16 * 1024 * 1024;
+
+/// A file's contents, either read into memory or, for big files, mapped.
+enum FileContents {
+    Read(Vec<u8>),
+    Mapped(Mmap),
+}
+
+impl std::ops::Deref for FileContents {
+    type Target = [u8];
 
-    let contents = conv_result(fs::read_to_string(filename))?;
-    if !string_is_printable(&contents) {
-        return Err(ReplaceError::FileNotPrintable);
+    fn deref(&self) -> &[u8] {
+        match self {
+            FileContents::Read(bytes) => bytes,
+            FileContents::Mapped(map) => map,
+        }
+    }
+}
+
+fn read_file(filename: &Path) -> Result<FileContents, ReplaceError> {
+    let mut file = conv_result(fs::File::open(filename))?;
+    let len = conv_result(file.metadata())?.len();
+
+    if len < MMAP_THRESHOLD {
+        let mut bytes = Vec::with_capacity(len as usize);
+        conv_result(file.read_to_end(&mut bytes))?;
+        return Ok(FileContents::Read(bytes));
     }
 
-    Ok(contents)
+    // Safety: the map is only read, but nothing stops another process from truncating the file while we're
+    // searching it. Touching a page past the new end then raises SIGBUS, which kills far outright instead of
+    // failing this one file. That's only risked for big files, where reading them whole isn't an option.
+    conv_result(unsafe { Mmap::map(&file) }).map(FileContents::Mapped)
 }
 
 fn get_lines_of_file(
-    filename: &str,
-) -> Result<impl Iterator<Item = io::Result<String>>, ReplaceError> {
-    if !file_is_printable(filename)? {
-        return Err(ReplaceError::FileNotPrintable);
+    filename: &Path,
+    options: &FarOptions,
+) -> Result<impl Iterator<Item = io::Result<(String, LineEnding)>>, ReplaceError> {
+    check_file_size(filename, options.max_filesize)?;
+
+    if conv_result(file_looks_binary(filename, options.binary_scan))? {
+        return Err(ReplaceError::FileIsBinary);
     }
 
     let file = conv_result(fs::File::open(filename))?;
-    return Ok(BufReader::with_capacity(16 * 1024, file).lines());
+    Ok(Lines::new(BufReader::with_capacity(16 * 1024, file)))
+}
+
+/// Writes the new contents of `filename` in a single pass, straight to a temp file unless it's a dry run, and
+/// checks whether they differ from `original` along the way. The temp file is only swapped in if they do.
+/// `write_new` returns where each match started.
+fn finish_replacement<F>(
+    filename: &Path,
+    original: &[u8],
+    options: &FarOptions,
+    write_new: F,
+) -> Result<FileReport, ReplaceError>
+where
+    F: FnOnce(&mut dyn Write) -> Result<Vec<usize>, ReplaceError>,
+{
+    let target = match options.dry_run {
+        true => None,
+        false => Some(conv_result(write_target(filename))?),
+    };
+    let mut tmp = match &target {
+        Some(target) => Some(conv_result(TempFile::new(target, NEW_SUFFIX))?),
+        None => None,
+    };
+
+    // A dry run only keeps the new contents when they're needed for the diff.
+    let mut new = Vec::new();
+    let mut report = FileReport::default();
+    {
+        let out: Box<dyn Write + '_> = match &mut tmp {
+            Some(tmp) => Box::new(BufWriter::new(&mut tmp.file)),
+            None if options.diff => Box::new(&mut new),
+            None => Box::new(io::sink()),
+        };
+        let mut detector = ChangeDetector::new(original, out);
+        report.add_offsets(original, write_new(&mut detector)?);
+        conv_result(detector.flush())?;
+        report.changed = detector.changed();
+    }
+
+    if !report.changed {
+        return Ok(report);
+    }
+
+    if options.diff {
+        if let Some(tmp) = &tmp {
+            new = conv_result(fs::read(&tmp.filename))?;
+        }
+        report.add_diff(filename, &String::from_utf8_lossy(original), &String::from_utf8_lossy(&new));
+    }
+
+    if let (Some(tmp), Some(target)) = (tmp, target) {
+        report.pending = swap_in(tmp, target, options)?;
+    }
+    Ok(report)
+}
+
+/// Writes the new contents to a temp file next to `filename` (or its target, for a symlink) and swaps it in.
+fn write_new_file<F>(filename: &Path, options: &FarOptions, write_new: F) -> Result<Option<PendingSwap>, ReplaceError>
+where
+    F: FnOnce(&mut dyn Write) -> Result<(), ReplaceError>,
+{
+    let target = conv_result(write_target(filename))?;
+    let mut tmp = conv_result(TempFile::new(&target, NEW_SUFFIX))?;
+    {
+        let mut out = BufWriter::new(&mut tmp.file);
+        write_new(&mut out)?;
+        conv_result(out.flush())?;
+    }
+
+    swap_in(tmp, target, options)
+}
+
+/// Swaps a finished temp file in for `target`, or with `--atomic-run` hands it back to be swapped in later.
+fn swap_in(tmp: TempFile, target: PathBuf, options: &FarOptions) -> Result<Option<PendingSwap>, ReplaceError> {
+    if options.atomic_run {
+        return Ok(Some(PendingSwap { tmp: tmp.keep(), target }));
+    }
+
+    conv_result(swap_in_now(&tmp.filename, &target, options))?;
+    Ok(None)
+}
+
+/// Keeps a backup of `target` first if one was asked for.
+fn swap_in_now(tmp: &Path, target: &Path, options: &FarOptions) -> io::Result<()> {
+    if let Some(backup) = &options.backup {
+        backup.save(target, tmp)?;
+    }
+
+    replace_file(tmp, target)
+}
+
+/// Swaps in every file prepared by an `--atomic-run`. The original of each file is copied aside first, so if one
+/// can't be swapped in, the ones before it are put back and the rest are thrown away.
+pub fn commit_swaps(swaps: &[PendingSwap], options: &FarOptions) -> Result<(), (PathBuf, io::Error)> {
+    let mut committed: Vec<(PathBuf, &Path)> = Vec::new();
+
+    for (i, swap) in swaps.iter().enumerate() {
+        let result = TempFile::new(&swap.target, OLD_SUFFIX).and_then(|old| {
+            fs::copy(&swap.target, &old.filename)?;
+            swap_in_now(&swap.tmp, &swap.target, options)?;
+            Ok(old.keep())
+        });
+
+        if let Err(e) = result {
+            for (old, target) in committed {
+                let _ = replace_file(&old, target);
+            }
+            discard_swaps(&swaps[i..]);
+            return Err((swap.target.clone(), e));
+        }
+
+        committed.push((result.expect("The error was handled above"), &swap.target));
+    }
+
+    for (old, _) in committed {
+        let _ = fs::remove_file(old);
+    }
+    Ok(())
+}
+
+/// Throws away the temp files of an `--atomic-run` that won't be committed.
+pub fn discard_swaps(swaps: &[PendingSwap]) {
+    for swap in swaps {
+        let _ = fs::remove_file(&swap.tmp);
+    }
+}
+
+/// Replaces in a file that has to be decoded before it can be searched. Unlike UTF-8 files, the whole file is
+/// decoded into memory, and it's written back in the encoding it came in, byte order mark and all.
+fn replace_in_decoded_file(
+    filename: &Path,
+    encoding: FileEncoding,
+    pattern: &TextPattern,
+    replacement: &str,
+    mode: FarMode,
+    options: &FarOptions,
+) -> Result<FileReport, ReplaceError> {
+    check_file_size(filename, options.max_filesize)?;
+
+    let original = conv_result(fs::read(filename))?;
+    if !encoding.is_utf16() && looks_binary(&original, options.binary_scan) {
+        return Err(ReplaceError::FileIsBinary);
+    }
+    let text = encoding.decode(&original).ok_or(ReplaceError::FileNotDecodable(encoding.name()))?;
+
+    let mut report = FileReport::default();
+    let new_text = match mode {
+        FarMode::All => {
+            let mut new = Vec::new();
+            let offsets = with_eol(options.eol, &mut new, |out| write_replaced(&text, pattern, replacement, out))?;
+            report.add_offsets(text.as_bytes(), offsets);
+            String::from_utf8(new).expect("Replacing in a string always gives a string")
+        }
+        FarMode::Lines => split_lines(&text)
+            .enumerate()
+            .map(|(i, (line, ending))| {
+                report.match_lines.extend(std::iter::repeat_n(i + 1, match_offsets(line, pattern)?.len()));
+                Ok(replace_string(line, pattern, replacement)? + ending.normalized(options.eol).as_str())
+            })
+            .collect::<Result<_, ReplaceError>>()?,
+    };
+
+    finish_text_replacement(filename, Some(encoding), &text, &new_text, report, options)
+}
+
+/// Writes `new_text` in place of `text` if they differ, encoding it the way the file was.
+fn finish_text_replacement(
+    filename: &Path,
+    encoding: Option<FileEncoding>,
+    text: &str,
+    new_text: &str,
+    mut report: FileReport,
+    options: &FarOptions,
+) -> Result<FileReport, ReplaceError> {
+    report.changed = new_text != text;
+    if !report.changed {
+        return Ok(report);
+    }
+
+    let new_bytes = match encoding {
+        Some(encoding) => Cow::Owned(encoding.encode(new_text).ok_or(ReplaceError::NotEncodable(encoding.name()))?),
+        None => Cow::Borrowed(new_text.as_bytes()),
+    };
+
+    if options.diff {
+        report.add_diff(filename, text, new_text);
+    }
+
+    if options.dry_run {
+        return Ok(report);
+    }
+
+    report.pending = write_new_file(filename, options, |out| Ok(out.write_all(&new_bytes)?))?;
+    Ok(report)
+}
+
+/// Reads a whole file as text, decoding it if it isn't UTF-8.
+fn read_text_file(filename: &Path, options: &FarOptions) -> Result<(Option<FileEncoding>, String), ReplaceError> {
+    check_file_size(filename, options.max_filesize)?;
+
+    let original = conv_result(fs::read(filename))?;
+    let encoding = FileEncoding::sniff(&original, options.encoding);
+    if !encoding.is_some_and(|e| e.is_utf16()) && looks_binary(&original, options.binary_scan) {
+        return Err(ReplaceError::FileIsBinary);
+    }
+
+    let text = match encoding {
+        Some(encoding) => encoding.decode(&original).ok_or(ReplaceError::FileNotDecodable(encoding.name()))?,
+        None => String::from_utf8(original).map_err(|_| ReplaceError::FileNotUtf8)?,
+    };
+
+    Ok((encoding, text))
+}
+
+/// Every match in `text` along with what it would be replaced with. In line mode, each match has to fit on a line.
+fn proposed_replacements(
+    text: &str,
+    pattern: &TextPattern,
+    replacement: &str,
+    mode: FarMode,
+) -> Result<Vec<(Range<usize>, String)>, ReplaceError> {
+    let mut proposals = Vec::new();
+    let mut propose = |offset: usize, input: &str| {
+        pattern.for_each_replacement(input, replacement, |m, new| {
+            proposals.push((m.start + offset..m.end + offset, new.to_string()));
+            Ok::<_, ReplaceError>(())
+        })
+    };
+
+    match mode {
+        FarMode::All => propose(0, text)?,
+        FarMode::Lines => {
+            let mut offset = 0;
+            for (line, ending) in split_lines(text) {
+                propose(offset, line)?;
+                offset += line.len() + ending.as_str().len();
+            }
+        }
+    }
+
+    Ok(proposals)
+}
+
+/// Replaces only the matches `decide` accepts, asking about them in order. The whole file is read into memory.
+/// Only accepted matches end up in the report.
+pub fn replace_interactively_in_file<F>(
+    filename: &Path,
+    pattern: &TextPattern,
+    replacement: &str,
+    mode: FarMode,
+    options: &FarOptions,
+    mut decide: F,
+) -> Result<FileReport, ReplaceError>
+where
+    F: FnMut(&str, Range<usize>, &str) -> Decision,
+{
+    let (encoding, text) = read_text_file(filename, options)?;
+
+    let mut report = FileReport::default();
+    let mut accepted = Vec::new();
+    let mut ask = true;
+
+    for (range, new) in proposed_replacements(&text, pattern, replacement, mode)? {
+        let decision = match ask {
+            true => decide(&text, range.clone(), &new),
+            false => Decision::Yes,
+        };
+
+        let new = match decision {
+            Decision::Yes => new,
+            Decision::No => continue,
+            Decision::All => {
+                ask = false;
+                new
+            }
+            Decision::Quit => break,
+            Decision::Edit(edited) => edited,
+        };
+
+        report.match_lines.push(count_line_endings(&text.as_bytes()[..range.start]) + 1);
+        accepted.push((range, new));
+    }
+
+    let new_text = conv_result(apply_replacements(&text, &accepted, options.eol))?;
+    finish_text_replacement(filename, encoding, &text, &new_text, report, options)
+}
+
+/// Replaces each range of `text` with the text that goes with it. The ranges have to be in order and not overlap.
+fn apply_replacements(text: &str, replacements: &[(Range<usize>, String)], eol: Option<LineEnding>) -> io::Result<String> {
+    let mut new_text = Vec::with_capacity(text.len());
+
+    with_eol(eol, &mut new_text, |out| {
+        let mut last = 0;
+        for (range, new) in replacements {
+            out.write_all(&text.as_bytes()[last..range.start])?;
+            out.write_all(new.as_bytes())?;
+            last = range.end;
+        }
+        out.write_all(&text.as_bytes()[last..])
+    })?;
+
+    Ok(String::from_utf8(new_text).expect("Replacing in a string always gives a string"))
+}
+
+fn rule_pattern(rule: &Rule) -> &TextPattern {
+    match &rule.pattern {
+        Pattern::Text(pattern) => pattern,
+        Pattern::Bytes(_) => unreachable!("--binary can't be combined with more than one rule"),
+    }
+}
+
+/// Every match of any of the rules, found in a single pass the way an alternation of them would be: the leftmost
+/// match wins, and of the matches starting at the same place, the first rule's. Nothing is matched twice,
+/// so rules like `a -> b` and `b -> a` swap the two. Rules in line mode only ever match within a line.
+fn simultaneous_replacements(text: &str, rules: &[Rule]) -> Result<Vec<(Range<usize>, String)>, ReplaceError> {
+    // Each line without its ending, for the rules in line mode to be matched against.
+    let mut lines = Vec::new();
+    let mut start = 0;
+    for (line, ending) in split_lines(text) {
+        lines.push(start..start + line.len());
+        start += line.len() + ending.as_str().len();
+    }
+
+    let find = |i: usize, pos: usize| {
+        let (pattern, replacement) = (rule_pattern(&rules[i]), &rules[i].replacement);
+
+        match rules[i].mode {
+            FarMode::All if pos <= text.len() => pattern.replacement_at(text, pos, replacement),
+            FarMode::All => Ok(None),
+            FarMode::Lines => {
+                for line in &lines[lines.partition_point(|line| line.end < pos)..] {
+                    let found = pattern.replacement_at(&text[line.clone()], pos.saturating_sub(line.start), replacement)?;
+                    if let Some((range, new)) = found {
+                        return Ok(Some((range.start + line.start..range.end + line.start, new)));
+                    }
+                }
+                Ok(None)
+            }
+        }
+    };
+
+    // The next match of each rule, which only has to be searched for again once the pass has moved past its start.
+    let mut next = (0..rules.len()).map(|i| find(i, 0)).collect::<Result<Vec<_>, _>>()?;
+    let mut replacements = Vec::new();
+    let mut pos = 0;
+
+    loop {
+        for (i, m) in next.iter_mut().enumerate() {
+            if m.as_ref().is_some_and(|(range, _)| range.start < pos) {
+                *m = find(i, pos)?;
+            }
+        }
+
+        let first = next
+            .iter()
+            .enumerate()
+            .filter_map(|(i, m)| m.as_ref().map(|(range, _)| (range.start, i)))
+            .min();
+        let (i, (range, new)) = match first {
+            Some((_, i)) => (i, next[i].take().expect("The rule has a match")),
+            None => break,
+        };
+
+        pos = match range.is_empty() {
+            true => text[range.end..].chars().next().map_or(text.len() + 1, |c| range.end + c.len_utf8()),
+            false => range.end,
+        };
+        next[i] = find(i, pos)?;
+        replacements.push((range, new));
+    }
+
+    Ok(replacements)
+}
+
+/// Applies each rule in turn to what the ones before it left, reading the file once and writing it once.
+/// The matches of each rule are reported by the line they're on when the rule is applied.
+///
+/// With `--simultaneous`, the rules are instead all applied in a single pass over the original text.
+pub fn replace_rules_in_file(filename: &Path, rules: &[Rule], options: &FarOptions) -> Result<FileReport, ReplaceError> {
+    let (encoding, text) = read_text_file(filename, options)?;
+
+    let mut report = FileReport::default();
+    let mut new_text = text.clone();
+
+    if options.simultaneous {
+        let replacements = simultaneous_replacements(&text, rules)?;
+
+        report.add_offsets(text.as_bytes(), replacements.iter().map(|(range, _)| range.start).collect());
+        new_text = conv_result(apply_replacements(&text, &replacements, None))?;
+    } else {
+        for rule in rules {
+            let replacements = proposed_replacements(&new_text, rule_pattern(rule), &rule.replacement, rule.mode)?;
+            if replacements.is_empty() {
+                continue;
+            }
+
+            report.add_offsets(new_text.as_bytes(), replacements.iter().map(|(range, _)| range.start).collect());
+            new_text = conv_result(apply_replacements(&new_text, &replacements, None))?;
+        }
+        report.match_lines.sort_unstable();
+    }
+
+    if options.eol.is_some() {
+        new_text = conv_result(apply_replacements(&new_text, &[], options.eol))?;
+    }
+
+    finish_text_replacement(filename, encoding, &text, &new_text, report, options)
 }
 
 pub fn replace_all_in_file(
-    filename: &str,
-    pattern: &Regex,
+    filename: &Path,
+    pattern: &TextPattern,
     replacement: &str,
-) -> Result<(), ReplaceError> {
-    let contents = get_contents_of_file(filename)?;
+    options: &FarOptions,
+) -> Result<FileReport, ReplaceError> {
+    if let Some(encoding) = conv_result(FileEncoding::sniff_file(filename, options.encoding))? {
+        return replace_in_decoded_file(filename, encoding, pattern, replacement, FarMode::All, options);
+    }
 
-    let mut tmp = conv_result(TempFile::new(filename, ".new"))?;
-    let new_contents = replace_string(&contents, pattern, replacement);
-    conv_result(write_to_file(
-        &mut tmp.file,
-        std::iter::once(Ok(new_contents)),
-    ))?;
+    check_file_size(filename, options.max_filesize)?;
 
-    conv_result(replace_file(&tmp.filename, filename))?;
-    Ok(())
+    let data = read_file(filename)?;
+    if looks_binary(&data, options.binary_scan) {
+        return Err(ReplaceError::FileIsBinary);
+    }
+    let contents = str::from_utf8(&data).map_err(|_| ReplaceError::FileNotUtf8)?;
+
+    // Most files don't match at all, and those are only searched up to their end without writing anything.
+    if options.eol.is_none() && pattern.find_iter(contents).next().transpose()?.is_none() {
+        return Ok(FileReport::default());
+    }
+
+    finish_replacement(filename, &data, options, |out| {
+        with_eol(options.eol, out, |out| write_replaced(contents, pattern, replacement, out))
+    })
+}
+
+/// Replaces matches of a `--binary` pattern, which works on raw bytes. In line mode, each match has to fit on a line.
+pub fn replace_bytes_in_file(
+    filename: &Path,
+    pattern: &bytes::Regex,
+    replacement: &str,
+    mode: FarMode,
+    options: &FarOptions,
+) -> Result<FileReport, ReplaceError> {
+    check_file_size(filename, options.max_filesize)?;
+
+    let data = read_file(filename)?;
+    let replacement = unescape_bytes(replacement);
+    let replacement = replacement.as_slice();
+
+    let matched = match mode {
+        FarMode::All => pattern.is_match(&data),
+        FarMode::Lines => split_byte_lines(&data).any(|(line, _)| pattern.is_match(line)),
+    };
+    if !matched && options.eol.is_none() {
+        return Ok(FileReport::default());
+    }
+
+    finish_replacement(filename, &data, options, |out| {
+        with_eol(options.eol, out, |out| match mode {
+            FarMode::All => Ok(write_bytes_replaced(&data, pattern, replacement, out)?),
+            FarMode::Lines => {
+                let mut offsets = Vec::new();
+                let mut offset = 0;
+                for (line, ending) in split_byte_lines(&data) {
+                    offsets.extend(write_bytes_replaced(line, pattern, replacement, out)?.into_iter().map(|o| o + offset));
+                    out.write_all(ending.as_str().as_bytes())?;
+                    offset += line.len() + ending.as_str().len();
+                }
+                Ok(offsets)
+            }
+        })
+    })
+}
+
+fn scan_lines_of_file(
+    filename: &Path,
+    pattern: &TextPattern,
+    replacement: &str,
+    options: &FarOptions,
+) -> Result<FileReport, ReplaceError> {
+    let mut report = FileReport::default();
+
+    for (i, line) in get_lines_of_file(filename, options)?.enumerate() {
+        let (line, ending) = conv_read_result(line)?;
+        let matches = match_offsets(&line, pattern)?.len();
+
+        if matches > 0 {
+            report.match_lines.extend(std::iter::repeat_n(i + 1, matches));
+            report.changed |= replace_string(&line, pattern, replacement)? != line;
+        }
+
+        report.changed |= ending.normalized(options.eol) != ending;
+    }
+
+    Ok(report)
 }
 
 pub fn replace_lines_in_file(
-    filename: &str,
-    pattern: &Regex,
+    filename: &Path,
+    pattern: &TextPattern,
     replacement: &str,
-) -> Result<(), ReplaceError> {
-    let lines = get_lines_of_file(filename)?;
+    options: &FarOptions,
+) -> Result<FileReport, ReplaceError> {
+    if let Some(encoding) = conv_result(FileEncoding::sniff_file(filename, options.encoding))? {
+        return replace_in_decoded_file(filename, encoding, pattern, replacement, FarMode::Lines, options);
+    }
 
-    let mut tmp = conv_result(TempFile::new(filename, ".new"))?;
+    let mut report = scan_lines_of_file(filename, pattern, replacement, options)?;
 
-    let new_lines = lines.map(|r| r.map(|l| replace_string(&l, pattern, replacement)));
-    let new_contents = Intersperse::new(new_lines, || Ok("\n".to_string()));
-    conv_result(write_to_file(&mut tmp.file, new_contents))?;
+    if !report.changed || (options.dry_run && !options.diff) {
+        return Ok(report);
+    }
 
-    conv_result(replace_file(&tmp.filename, filename))?;
-    Ok(())
+    let lines = get_lines_of_file(filename, options)?;
+    let target = conv_result(write_target(filename))?;
+    let mut tmp = match options.dry_run {
+        true => None,
+        false => Some(conv_result(TempFile::new(&target, NEW_SUFFIX))?),
+    };
+
+    let mut new = String::new();
+
+    {
+        let mut new_contents = lines
+            .map(|r| {
+                let (l, e) = conv_read_result(r)?;
+                Ok(replace_string(&l, pattern, replacement)? + e.normalized(options.eol).as_str())
+            })
+            .inspect(|r| {
+                if let (true, Ok(s)) = (options.diff, r) {
+                    new += s;
+                }
+            });
+
+        match &mut tmp {
+            Some(tmp) => write_to_file(&mut tmp.file, new_contents)?,
+            None => new_contents.try_for_each(|r| r.map(|_| ()))?,
+        };
+    }
+
+    if options.diff {
+        let original = conv_result(fs::read_to_string(filename))?;
+        report.add_diff(filename, &original, &new);
+    }
+
+    if let Some(tmp) = tmp {
+        report.pending = swap_in(tmp, target, options)?;
+    }
+    Ok(report)
 }
 
 #[cfg(test)]
@@ -147,12 +868,24 @@ mod tests {
     use super::*;
 
     pub fn _test_replace_string(regex: &str, input: &str, replacement: &str, expected: &str) {
-        let re = fancy_regex::Regex::new(regex).expect(&format!("Invalid regex {}", regex));
-        let result = replace_string(input, &re, replacement);
+        let re = TextPattern::regex(regex).unwrap_or_else(|_| panic!("Invalid regex {}", regex));
+        let result = replace_string(input, &re, replacement).unwrap();
 
         debug_assert_eq!(result, expected);
     }
 
+    #[test]
+    pub fn test_file_report_lines() {
+        let contents = "abc\ndef abc\n\nabc abc";
+        let re = TextPattern::regex("abc").unwrap();
+
+        let mut report = FileReport::default();
+        report.add_offsets(contents.as_bytes(), match_offsets(contents, &re).unwrap());
+
+        debug_assert_eq!(report.match_lines, vec![1, 2, 4, 4]);
+        debug_assert_eq!(report.matches(), 4);
+    }
+
     #[test]
     pub fn test_replace_string() {
         let cases = vec![
@@ -170,4 +903,276 @@ mod tests {
             _test_replace_string(regex, input, replacement, expected);
         }
     }
+
+    fn _test_file_is_untouched(contents: &str, regex: &str, replacement: &str) {
+        use crate::testdir::testdir::TestFile;
+
+        let file = TestFile::new(contents);
+        let re = TextPattern::regex(regex).unwrap();
+        let before = fs::metadata(file.path_str()).unwrap();
+
+        for f in [replace_all_in_file, replace_lines_in_file] {
+            let report = f(file.path(), &re, replacement, &FarOptions::default()).unwrap();
+            debug_assert!(!report.changed);
+        }
+
+        let after = fs::metadata(file.path_str()).unwrap();
+        debug_assert_eq!(before.modified().unwrap(), after.modified().unwrap());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            debug_assert_eq!(before.ino(), after.ino());
+        }
+
+        debug_assert_eq!(fs::read_to_string(file.path_str()).unwrap(), contents);
+    }
+
+    #[test]
+    pub fn test_unmatched_file_is_untouched() {
+        _test_file_is_untouched("abc\ndef\n", "xyz", "ghi");
+    }
+
+    #[test]
+    pub fn test_file_replaced_with_itself_is_untouched() {
+        _test_file_is_untouched("abc\ndef\n", "abc", "abc");
+    }
+
+    #[test]
+    pub fn test_file_is_untouched_when_the_regex_fails() {
+        use crate::testdir::testdir::TestFile;
+
+        let contents = format!("x {}c x\n", "a".repeat(40));
+        let file = TestFile::new(&contents);
+        let re = TextPattern::regex(r"(?=x)x|(a|a)*\1b|x").unwrap();
+
+        for f in [replace_all_in_file, replace_lines_in_file] {
+            let result = f(file.path(), &re, "Y", &FarOptions::default());
+            debug_assert!(matches!(result, Err(ReplaceError::RegexFailed(_))));
+        }
+
+        let rules = [
+            Rule { pattern: Pattern::Text(re), replacement: "Y".to_string(), mode: FarMode::All },
+            Rule { pattern: Pattern::Text(TextPattern::literal("c", false, false)), replacement: "d".to_string(), mode: FarMode::Lines },
+        ];
+        let options = FarOptions { simultaneous: true, ..FarOptions::default() };
+        let result = replace_rules_in_file(file.path(), &rules, &options);
+        debug_assert!(matches!(result, Err(ReplaceError::RegexFailed(_))));
+
+        debug_assert_eq!(fs::read_to_string(file.path_str()).unwrap(), contents);
+    }
+
+    #[test]
+    pub fn test_simultaneous_rules_keep_their_modes() {
+        use crate::pattern::PatternOptions;
+        use crate::rules::parse_rules;
+
+        let rules = parse_rules("s/a\\s*$//\ns/zzz/q/m\ns/b\\n//m\n", FarMode::Lines, &PatternOptions::default()).unwrap();
+        let text = "a \nb\nzzz";
+        let replacements = simultaneous_replacements(text, &rules).unwrap();
+
+        debug_assert_eq!(apply_replacements(text, &replacements, None).unwrap(), "\nq");
+    }
+
+    #[test]
+    pub fn test_changed_file_is_reported() {
+        use crate::testdir::testdir::TestFile;
+
+        let file = TestFile::new("abc\ndef");
+        let re = TextPattern::regex("abc").unwrap();
+
+        let report = replace_lines_in_file(file.path(), &re, "xyz", &FarOptions::default()).unwrap();
+
+        debug_assert!(report.changed);
+        debug_assert_eq!(fs::read_to_string(file.path_str()).unwrap(), "xyz\ndef");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    pub fn test_replace_writes_through_symlinks() {
+        use crate::testdir::testdir::TestDir;
+
+        let mut temp = TestDir::new();
+        temp.file("a", "abc\n").symlink("b", "a");
+        let path = |name: &str| temp.path().join(name);
+        let re = TextPattern::regex("abc").unwrap();
+
+        replace_all_in_file(&path("b"), &re, "def", &FarOptions::default()).unwrap();
+        debug_assert!(fs::symlink_metadata(path("b")).unwrap().file_type().is_symlink());
+        debug_assert_eq!(fs::read_to_string(path("a")).unwrap(), "def\n");
+
+        replace_lines_in_file(&path("b"), &re, "def", &FarOptions::default()).unwrap();
+        let re = TextPattern::regex("def").unwrap();
+        replace_lines_in_file(&path("b"), &re, "ghi", &FarOptions::default()).unwrap();
+        debug_assert!(fs::symlink_metadata(path("b")).unwrap().file_type().is_symlink());
+        debug_assert_eq!(fs::read_to_string(path("a")).unwrap(), "ghi\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    pub fn test_replace_in_non_utf8_filename() {
+        use crate::testdir::testdir::TestDir;
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let temp = TestDir::new();
+        let path = temp.path().join(OsStr::from_bytes(b"caf\xe9"));
+        fs::write(&path, "abc\n").unwrap();
+        let re = TextPattern::regex("abc").unwrap();
+
+        let options = FarOptions { diff: true, ..FarOptions::default() };
+        let report = replace_all_in_file(&path, &re, "def", &options).unwrap();
+
+        debug_assert!(report.changed);
+        debug_assert!(report.diff.unwrap().starts_with(&format!("--- {}", path.display())));
+        debug_assert_eq!(fs::read_to_string(&path).unwrap(), "def\n");
+    }
+
+    #[test]
+    pub fn test_replace_lines_keeps_line_endings() {
+        use crate::testdir::testdir::TestFile;
+
+        let contents = "abc\r\nabc\rx abc\nabc";
+        let file = TestFile::new(contents);
+        let re = TextPattern::regex("abc$").unwrap();
+
+        let report = replace_lines_in_file(file.path(), &re, "def", &FarOptions::default()).unwrap();
+
+        debug_assert_eq!(report.match_lines, vec![1, 2, 3, 4]);
+        debug_assert_eq!(fs::read_to_string(file.path()).unwrap(), "def\r\ndef\rx def\ndef");
+
+        fs::write(file.path(), "abc\n").unwrap();
+        replace_lines_in_file(file.path(), &re, "def", &FarOptions::default()).unwrap();
+        debug_assert_eq!(fs::read_to_string(file.path()).unwrap(), "def\n");
+    }
+
+    #[test]
+    pub fn test_eol_normalizes_line_endings() {
+        use crate::testdir::testdir::TestFile;
+
+        let re = TextPattern::regex("xyz").unwrap();
+        let options = FarOptions { eol: Some(LineEnding::CrLf), ..FarOptions::default() };
+
+        for f in [replace_all_in_file, replace_lines_in_file] {
+            let file = TestFile::new("abc\ndef\r\nghi\rjkl");
+
+            let report = f(file.path(), &re, "", &options).unwrap();
+
+            debug_assert!(report.changed);
+            debug_assert_eq!(fs::read_to_string(file.path()).unwrap(), "abc\r\ndef\r\nghi\r\njkl");
+        }
+    }
+
+    #[test]
+    pub fn test_file_over_max_filesize_is_skipped() {
+        use crate::testdir::testdir::TestFile;
+
+        let file = TestFile::new("abc\n");
+        let re = TextPattern::regex("abc").unwrap();
+        let options = FarOptions { max_filesize: Some(3), ..FarOptions::default() };
+
+        for f in [replace_all_in_file, replace_lines_in_file] {
+            match f(file.path(), &re, "def", &options) {
+                Err(ReplaceError::FileTooBig { size: 4, limit: 3 }) => {}
+                r => panic!("The file should be too big, got {:?}", r),
+            }
+        }
+        debug_assert_eq!(fs::read_to_string(file.path()).unwrap(), "abc\n");
+    }
+
+    #[test]
+    pub fn test_big_files_are_mapped() {
+        use crate::testdir::testdir::TestFile;
+
+        let small = TestFile::new("abc\n");
+        debug_assert!(matches!(read_file(small.path()).unwrap(), FileContents::Read(_)));
+
+        let mut text = "x".repeat(MMAP_THRESHOLD as usize);
+        text.push_str("abc\n");
+        let big = TestFile::new(text.as_str());
+        debug_assert!(matches!(read_file(big.path()).unwrap(), FileContents::Mapped(_)));
+
+        let re = TextPattern::regex("abc").unwrap();
+        replace_all_in_file(big.path(), &re, "def", &FarOptions::default()).unwrap();
+        debug_assert!(fs::read_to_string(big.path()).unwrap().ends_with("xdef\n"));
+    }
+
+    #[test]
+    pub fn test_write_replaced_matches_regex_replace_all() {
+        let input = "John Doe\r\nJane Roe\n";
+        let regex = fancy_regex::Regex::new("(\\w+) (\\w+)").unwrap();
+        let re = TextPattern::Regex(regex.clone());
+
+        let mut out = Vec::new();
+        write_replaced(input, &re, "$2, $1", &mut out).unwrap();
+        debug_assert_eq!(String::from_utf8(out).unwrap(), regex.replace_all(input, "$2, $1"));
+
+        let mut detector = ChangeDetector::new(input.as_bytes(), io::sink());
+        write_replaced(input, &re, "$1 $2", &mut detector).unwrap();
+        debug_assert!(!detector.changed());
+    }
+
+    #[test]
+    pub fn test_replace_keeps_encoding_and_bom() {
+        use crate::testdir::testdir::TestFile;
+
+        let re = TextPattern::regex("^caf\u{e9}").unwrap();
+        let utf16 = b"\xff\xfec\0a\0f\0\xe9\0\r\0\n\0";
+        let utf8 = b"\xef\xbb\xbfcaf\xc3\xa9\r\n";
+
+        for f in [replace_all_in_file, replace_lines_in_file] {
+            for (before, after) in [(&utf16[..], &b"\xff\xfet\0e\0a\0\r\0\n\0"[..]), (&utf8[..], &b"\xef\xbb\xbftea\r\n"[..])] {
+                let file = TestFile::new(before);
+                let options = FarOptions { diff: true, ..FarOptions::default() };
+
+                let report = f(file.path(), &re, "tea", &options).unwrap();
+
+                debug_assert_eq!(report.match_lines, vec![1]);
+                debug_assert!(report.diff.unwrap().contains("-caf\u{e9}\r\n+tea\r\n"));
+                debug_assert_eq!(fs::read(file.path()).unwrap(), after);
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_replace_with_forced_encoding() {
+        use crate::encoding::encoding_for_label;
+        use crate::testdir::testdir::TestFile;
+
+        let file = TestFile::new(b"caf\xe9\n");
+        let re = TextPattern::regex("\u{e9}").unwrap();
+
+        match replace_lines_in_file(file.path(), &re, "e", &FarOptions::default()) {
+            Err(ReplaceError::FileNotUtf8) => {}
+            r => panic!("The file shouldn't be UTF-8, got {:?}", r),
+        }
+
+        let options = FarOptions { encoding: encoding_for_label("latin1"), ..FarOptions::default() };
+        match replace_lines_in_file(file.path(), &re, "\u{3042}", &options) {
+            Err(ReplaceError::NotEncodable(_)) => {}
+            r => panic!("The replacement shouldn't be encodable, got {:?}", r),
+        }
+
+        replace_lines_in_file(file.path(), &re, "\u{e8}", &options).unwrap();
+        debug_assert_eq!(fs::read(file.path()).unwrap(), b"caf\xe8\n");
+    }
+
+    #[test]
+    pub fn test_replace_bytes_writes_escaped_bytes() {
+        use crate::testdir::testdir::TestFile;
+
+        let file = TestFile::new(b"caf\xe9 caf\xe9\n");
+        let re = bytes::RegexBuilder::new("caf\\xe9").unicode(false).build().unwrap();
+
+        replace_bytes_in_file(file.path(), &re, "caf\\xe8", FarMode::All, &FarOptions::default()).unwrap();
+        debug_assert_eq!(fs::read(file.path()).unwrap(), b"caf\xe8 caf\xe8\n");
+    }
+
+    #[test]
+    pub fn test_replace_literal() {
+        let re = TextPattern::literal("Vec<T>", false, false);
+
+        debug_assert_eq!(replace_string("Vec<T> VecT Vec<T>", &re, "$1<T>").unwrap(), "$1<T> VecT $1<T>");
+        debug_assert_eq!(match_offsets("Vec<T> VecT Vec<T>", &re).unwrap(), vec![0, 12]);
+    }
 }
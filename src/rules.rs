@@ -0,0 +1,173 @@
+use crate::input::FarMode;
+use crate::pattern::{CaseMode, Pattern, PatternError, PatternOptions};
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// A pattern, what to replace it with, and whether it's matched line by line or against the whole file.
+#[derive(Debug)]
+pub struct Rule {
+    pub pattern: Pattern,
+    pub replacement: String,
+    pub mode: FarMode,
+}
+
+impl Rule {
+    pub fn new(pattern: &str, replacement: &str, mode: FarMode, options: &PatternOptions) -> Result<Rule, PatternError> {
+        if options.preserve_case {
+            return Ok(Rule {
+                pattern: Pattern::preserving_case(pattern, replacement, options.whole_words),
+                replacement: replacement.to_string(),
+                mode,
+            });
+        }
+
+        let pattern = Pattern::new(pattern, options)?;
+        Ok(Rule {
+            replacement: pattern.replacement(replacement, options),
+            pattern,
+            mode,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum RulesError {
+    IOError(io::Error),
+    /// A line that isn't a rule, by its (1-based) line number.
+    Syntax(usize, String),
+    Pattern(usize, PatternError),
+}
+
+impl Display for RulesError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            RulesError::IOError(e) => write!(f, "{}", e),
+            RulesError::Syntax(line, msg) => write!(f, "line {}: {}", line, msg),
+            RulesError::Pattern(line, PatternError::Text(e)) => write!(f, "line {}: Invalid regex: {}", line, e),
+            RulesError::Pattern(line, PatternError::Bytes(e)) => write!(f, "line {}: Invalid regex: {}", line, e),
+        }
+    }
+}
+
+/// Splits `a/b/c` on the delimiter, turning an escaped delimiter into a plain one. Every other escape is kept as is.
+fn split_unescaped(s: &str, delimiter: char) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        let part = parts.last_mut().expect("There's always a part");
+        match c {
+            '\\' => match chars.next() {
+                Some(next) if next == delimiter => part.push(next),
+                Some(next) => {
+                    part.push('\\');
+                    part.push(next);
+                }
+                None => part.push('\\'),
+            },
+            c if c == delimiter => parts.push(String::new()),
+            c => part.push(c),
+        }
+    }
+
+    parts
+}
+
+/// Applies a rule's flags on top of the ones given on the command line.
+fn apply_flags(flags: &str, mut mode: FarMode, mut options: PatternOptions) -> Result<(FarMode, PatternOptions), String> {
+    for flag in flags.chars() {
+        match flag {
+            'i' => options.case = CaseMode::Insensitive,
+            'S' => options.case = CaseMode::Smart,
+            'F' => options.fixed_strings = true,
+            'w' => options.whole_words = true,
+            'P' => options.preserve_case = true,
+            'm' => mode = FarMode::All,
+            c => return Err(format!("'{}' is not a rule flag", c)),
+        }
+    }
+
+    if let Some(flag) = options.preserve_case_conflict() {
+        return Err(format!("preserve case ('P') can't be used with {}", flag));
+    }
+
+    Ok((mode, options))
+}
+
+/// Parses rules like sed's `s/pattern/replacement/flags`, one per line. Any character can stand in for the `/`.
+/// Blank lines and lines starting with `#` are skipped.
+///
+/// The flags are `i` (ignore case), `S` (smart case), `F` (fixed strings), `w` (whole words), `P` (preserve case)
+/// and `m` (match the whole file instead of line by line).
+pub fn parse_rules(text: &str, mode: FarMode, options: &PatternOptions) -> Result<Vec<Rule>, RulesError> {
+    let mut rules = Vec::new();
+
+    for (i, line) in text.lines().enumerate() {
+        let number = i + 1;
+        let line = line.trim_start();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut chars = line.chars();
+        let delimiter = match (chars.next(), chars.next()) {
+            (Some('s'), Some(d)) if !d.is_alphanumeric() && d != '\\' && !d.is_whitespace() => d,
+            _ => return Err(RulesError::Syntax(number, "A rule has to look like s/pattern/replacement/flags".to_string())),
+        };
+
+        let parts = split_unescaped(chars.as_str(), delimiter);
+        let (pattern, replacement, flags) = match parts.as_slice() {
+            [pattern, replacement, flags] => (pattern, replacement, flags.trim_end()),
+            _ => return Err(RulesError::Syntax(number, format!("A rule needs exactly three '{}'s", delimiter))),
+        };
+
+        let (mode, options) = apply_flags(flags, mode, *options).map_err(|e| RulesError::Syntax(number, e))?;
+        rules.push(Rule::new(pattern, replacement, mode, &options).map_err(|e| RulesError::Pattern(number, e))?);
+    }
+
+    Ok(rules)
+}
+
+pub fn read_rules(path: &Path, mode: FarMode, options: &PatternOptions) -> Result<Vec<Rule>, RulesError> {
+    let text = fs::read_to_string(path).map_err(RulesError::IOError)?;
+    parse_rules(&text, mode, options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_unescaped() {
+        debug_assert_eq!(split_unescaped(r"a\/b/c\d/", '/'), vec!["a/b", r"c\d", ""]);
+        debug_assert_eq!(split_unescaped("a|b/c|", '|'), vec!["a", "b/c", ""]);
+    }
+
+    #[test]
+    fn test_parse_rules() {
+        let text = "# renames\n\ns/user_?id/account_id/i\n  s|a.b|x/y|Fm\ns/(\\w+)\\/(\\w+)/$2-$1/\n";
+        let rules = parse_rules(text, FarMode::Lines, &PatternOptions::default()).unwrap();
+
+        debug_assert_eq!(rules.len(), 3);
+        debug_assert!(rules[0].pattern.is_match("USERID"));
+        debug_assert_eq!(rules[0].mode, FarMode::Lines);
+        debug_assert!(rules[1].pattern.is_match("a.b") && !rules[1].pattern.is_match("axb"));
+        debug_assert_eq!(rules[1].replacement, "x/y");
+        debug_assert_eq!(rules[1].mode, FarMode::All);
+        debug_assert!(rules[2].pattern.is_match("a/b"));
+    }
+
+    #[test]
+    fn test_parse_rules_errors() {
+        let parse = |text: &str| parse_rules(text, FarMode::Lines, &PatternOptions::default()).map(|r| r.len());
+
+        debug_assert!(matches!(parse("s/a/b/\nx/a/b/"), Err(RulesError::Syntax(2, _))));
+        debug_assert!(matches!(parse("s/a/b"), Err(RulesError::Syntax(1, _))));
+        debug_assert!(matches!(parse("s/a/b/q"), Err(RulesError::Syntax(1, _))));
+        debug_assert!(matches!(parse("s/(/b/"), Err(RulesError::Pattern(1, _))));
+        debug_assert!(matches!(parse("s/a/b/w\ns/a/b/Pi"), Err(RulesError::Syntax(2, _))));
+        debug_assert!(matches!(parse("s/a/b/FP"), Err(RulesError::Syntax(1, _))));
+    }
+}
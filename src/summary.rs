@@ -0,0 +1,183 @@
+use crate::replace::{FileReport, ReplaceError};
+use std::fmt::{self, Display, Formatter};
+use std::ops::Add;
+use std::time::Duration;
+
+/// Something matched, and everything that was searched was searched without errors.
+pub const EXIT_MATCHED: i32 = 0;
+/// Nothing matched, like `grep`.
+pub const EXIT_NO_MATCH: i32 = 1;
+/// The command line couldn't be parsed.
+pub const EXIT_USAGE: i32 = 2;
+/// Some files or paths couldn't be read or written.
+pub const EXIT_PARTIAL_FAILURE: i32 = 3;
+
+/// What a run did, added up over every file it visited.
+/// Skipped files count as scanned, and in a dry run `files_changed` counts the files that would have changed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub files_scanned: usize,
+    pub files_matched: usize,
+    pub files_changed: usize,
+    pub replacements: usize,
+    /// Files over `--max-filesize`.
+    pub skipped_too_big: usize,
+    /// Files that look binary, aren't valid UTF-8 or aren't valid in their encoding.
+    pub skipped_not_printable: usize,
+    /// Files that couldn't be read or written, and paths that couldn't be walked.
+    pub errors: usize,
+    pub elapsed: Duration,
+}
+
+impl Summary {
+    pub fn from_report(report: &FileReport) -> Summary {
+        Summary {
+            files_scanned: 1,
+            files_matched: (report.matches() > 0) as usize,
+            files_changed: report.changed as usize,
+            replacements: if report.changed { report.matches() } else { 0 },
+            ..Summary::default()
+        }
+    }
+
+    pub fn from_error(error: &ReplaceError) -> Summary {
+        let mut summary = Summary { files_scanned: 1, ..Summary::default() };
+
+        match error {
+            ReplaceError::FileTooBig { .. } => summary.skipped_too_big = 1,
+            ReplaceError::FileIsBinary | ReplaceError::FileNotUtf8 | ReplaceError::FileNotDecodable(_) => {
+                summary.skipped_not_printable = 1
+            }
+            ReplaceError::NotEncodable(_) | ReplaceError::RegexFailed(_) | ReplaceError::IOError(_) => summary.errors = 1,
+        }
+
+        summary
+    }
+
+    pub fn walk_error() -> Summary {
+        Summary { errors: 1, ..Summary::default() }
+    }
+
+    /// Errors win over matches, since a script can't trust a run that only changed some of the files.
+    /// Skipped files aren't errors.
+    pub fn exit_code(&self) -> i32 {
+        match (self.errors, self.files_matched) {
+            (0, 0) => EXIT_NO_MATCH,
+            (0, _) => EXIT_MATCHED,
+            _ => EXIT_PARTIAL_FAILURE,
+        }
+    }
+
+    pub fn skipped(&self) -> usize {
+        self.skipped_too_big + self.skipped_not_printable
+    }
+
+    /// The breakdown printed by `--stats`, one count per line.
+    pub fn details(&self) -> String {
+        let rows = [
+            ("files scanned", self.files_scanned.to_string()),
+            ("files matched", self.files_matched.to_string()),
+            ("files changed", self.files_changed.to_string()),
+            ("replacements", self.replacements.to_string()),
+            ("skipped, too big", self.skipped_too_big.to_string()),
+            ("skipped, not printable", self.skipped_not_printable.to_string()),
+            ("errors", self.errors.to_string()),
+            ("elapsed", format!("{:.3}s", self.elapsed.as_secs_f64())),
+        ];
+
+        rows.iter().map(|(name, value)| format!("{:<24}{}\n", format!("{}:", name), value)).collect()
+    }
+}
+
+impl Add for Summary {
+    type Output = Summary;
+
+    fn add(self, other: Summary) -> Summary {
+        Summary {
+            files_scanned: self.files_scanned + other.files_scanned,
+            files_matched: self.files_matched + other.files_matched,
+            files_changed: self.files_changed + other.files_changed,
+            replacements: self.replacements + other.replacements,
+            skipped_too_big: self.skipped_too_big + other.skipped_too_big,
+            skipped_not_printable: self.skipped_not_printable + other.skipped_not_printable,
+            errors: self.errors + other.errors,
+            elapsed: self.elapsed + other.elapsed,
+        }
+    }
+}
+
+fn plural(n: usize, word: &str) -> String {
+    match n {
+        1 => format!("{} {}", n, word),
+        _ => format!("{} {}s", n, word),
+    }
+}
+
+impl Display for Summary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} changed, {} ({} scanned, {} skipped, {}) in {:.2}s",
+            plural(self.files_changed, "file"),
+            plural(self.replacements, "replacement"),
+            self.files_scanned,
+            self.skipped(),
+            plural(self.errors, "error"),
+            self.elapsed.as_secs_f64()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn test_summary_adds_up() {
+        let changed = FileReport { match_lines: vec![1, 1, 3], changed: true, ..FileReport::default() };
+        let unchanged = FileReport { match_lines: vec![2], changed: false, ..FileReport::default() };
+
+        let summary = Summary::from_report(&changed)
+            + Summary::from_report(&unchanged)
+            + Summary::from_report(&FileReport::default())
+            + Summary::from_error(&ReplaceError::FileTooBig { size: 10, limit: 5 })
+            + Summary::from_error(&ReplaceError::FileIsBinary)
+            + Summary::from_error(&ReplaceError::IOError(io::Error::other("oops")))
+            + Summary::walk_error();
+
+        let expected = Summary {
+            files_scanned: 6,
+            files_matched: 2,
+            files_changed: 1,
+            replacements: 3,
+            skipped_too_big: 1,
+            skipped_not_printable: 1,
+            errors: 2,
+            elapsed: Duration::default(),
+        };
+        debug_assert_eq!(summary, expected);
+        debug_assert_eq!(summary.to_string(), "1 file changed, 3 replacements (6 scanned, 2 skipped, 2 errors) in 0.00s");
+    }
+
+    #[test]
+    fn test_summary_details() {
+        let summary = Summary { files_scanned: 4, elapsed: Duration::from_millis(1500), ..Summary::default() };
+        let details = summary.details();
+
+        debug_assert!(details.starts_with("files scanned:          4\n"));
+        debug_assert!(details.ends_with("elapsed:                1.500s\n"));
+        debug_assert_eq!(details.lines().count(), 8);
+    }
+
+    #[test]
+    fn test_exit_code() {
+        let matched = Summary { files_scanned: 2, files_matched: 1, ..Summary::default() };
+        let skipped = Summary { files_scanned: 1, skipped_not_printable: 1, ..Summary::default() };
+
+        debug_assert_eq!(matched.exit_code(), EXIT_MATCHED);
+        debug_assert_eq!(Summary::default().exit_code(), EXIT_NO_MATCH);
+        debug_assert_eq!(skipped.exit_code(), EXIT_NO_MATCH);
+        debug_assert_eq!((matched + Summary::walk_error()).exit_code(), EXIT_PARTIAL_FAILURE);
+    }
+}
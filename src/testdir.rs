@@ -1,4 +1,5 @@
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 pub mod testdir {
     use rand::{distributions::Alphanumeric, Rng};
     use std::env;
@@ -14,17 +15,17 @@ pub mod testdir {
             .collect()
     }
 
-    fn pathbuf_to_str(pb: &PathBuf) -> String {
+    fn pathbuf_to_str(pb: &Path) -> String {
         pb.to_str().expect("The OS should be using UTF-8 strings").to_string()
     }
 
-    fn pathbuf_concat(pb: &PathBuf, s: &str) -> PathBuf {
-        let mut ret = pb.clone();
+    fn pathbuf_concat(pb: &Path, s: &str) -> PathBuf {
+        let mut ret = pb.to_path_buf();
         ret.push(s);
         ret
     }
 
-    fn make_until_valid_path<T, F: FnMut(PathBuf) -> io::Result<T>>(dir: &PathBuf, mut func: F) -> (T, PathBuf) {
+    fn make_until_valid_path<T, F: FnMut(PathBuf) -> io::Result<T>>(dir: &Path, mut func: F) -> (T, PathBuf) {
         loop {
             let try_pb = pathbuf_concat(dir, &random_name());
             let try_str = pathbuf_to_str(&try_pb);
@@ -48,7 +49,7 @@ pub mod testdir {
         pub fn new() -> TestDir {
             let (_, path) = make_until_valid_path(&env::temp_dir(), fs::create_dir);
             TestDir {
-                path: path,
+                path,
                 subdirs: Vec::new()
             }
         }
@@ -59,29 +60,29 @@ pub mod testdir {
 
             let path_str = fname.to_str().expect("Imagine using an OS without UTF-8 filenames").to_string();
 
-            let _ = fs::File::create(&fname)
-                .expect(&format!(
-                    "Could not create file {}",
-                    path_str
+            fs::File::create(&fname)
+                .unwrap_or_else(|e| panic!(
+                    "Could not create file {}: {}",
+                    path_str, e
                 ))
-                .write(contents.as_bytes())
-                .expect(&format!(
-                    "Couldn't write to file {}",
-                    path_str
+                .write_all(contents.as_bytes())
+                .unwrap_or_else(|e| panic!(
+                    "Couldn't write to file {}: {}",
+                    path_str, e
                 ));
 
             self
         }
 
-        pub fn subdir<F: FnOnce(&mut TestDir) -> ()>(&mut self, name: &str, func: F) -> &mut TestDir {
+        pub fn subdir<F: FnOnce(&mut TestDir)>(&mut self, name: &str, func: F) -> &mut TestDir {
             let path = pathbuf_concat(&self.path, name);
-            let _ = fs::create_dir(&path).expect(&format!(
-                "Could not create subdir {}. Does it already exist?",
-                name
+            fs::create_dir(&path).unwrap_or_else(|e| panic!(
+                "Could not create subdir {}. Does it already exist? {}",
+                name, e
             ));
 
             let mut dir = TestDir {
-                path: path,
+                path,
                 subdirs: Vec::new()
             };
 
@@ -90,12 +91,19 @@ pub mod testdir {
             self
         }
 
-        pub fn path(&self) -> &Path {
-            &self.path
+        #[cfg(unix)]
+        pub fn symlink(&mut self, name: &str, target: &str) -> &mut TestDir {
+            let path = pathbuf_concat(&self.path, name);
+            std::os::unix::fs::symlink(target, &path).unwrap_or_else(|e| panic!(
+                "Could not create symlink {}: {}",
+                name, e
+            ));
+
+            self
         }
 
-        pub fn path_str(&self) -> &str {
-            self.path.as_path().to_str().unwrap()
+        pub fn path(&self) -> &Path {
+            &self.path
         }
     }
 
@@ -110,9 +118,9 @@ pub mod testdir {
     }
 
     impl TestFile {
-        pub fn new(contents: &str) -> TestFile {
+        pub fn new<C: AsRef<[u8]>>(contents: C) -> TestFile {
             let (_, pb) = make_until_valid_path(&env::temp_dir(), |pb| {
-                fs::write(pb, contents)
+                fs::write(pb, contents.as_ref())
             });
 
             TestFile {
@@ -120,6 +128,10 @@ pub mod testdir {
             }
         }
 
+        pub fn path(&self) -> &Path {
+            &self.path
+        }
+
         pub fn path_str(&self) -> String {
             pathbuf_to_str(&self.path)
         }